@@ -34,21 +34,57 @@
 
 
 extern crate libc;
-extern crate magic_sys as ffi;
+extern crate magic_sys;
 #[macro_use]
 extern crate bitflags;
 
-use libc::{size_t, c_char};
-use std::path::Path;
-use std::str;
+use libc::{size_t, c_char, c_int};
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 use std::error;
 use std::fmt::Display;
 use std::ffi::{CStr, CString};
 
+pub mod rules;
+
+
+/// The raw `libmagic` declarations, for calls this wrapper lacks
+///
+/// These are the declarations of the `magic-sys` crate, plus the ones it
+/// lacks, and with `magic_errno` returning an `int` like the C function
+/// does. Combine with `Cookie::into_raw` and `Cookie::from_raw` to pass
+/// cookies between the two.
+pub mod ffi {
+    use libc::{size_t, c_int, c_void};
+
+    pub use magic_sys::{Magic, magic_open, magic_close, magic_error, magic_descriptor, magic_file, magic_buffer,
+                        magic_setflags, magic_check, magic_compile, magic_list, magic_load};
+
+    // `magic-sys` 0.2 declares `magic_errno` as returning a pointer and lacks the others
+    extern "C" {
+        pub fn magic_errno(cookie: *const Magic) -> c_int;
+        pub fn magic_version() -> c_int;
+        pub fn magic_setparam(cookie: *const Magic, param: c_int, value: *const c_void) -> c_int;
+        pub fn magic_getparam(cookie: *const Magic, param: c_int, value: *mut c_void) -> c_int;
+        pub fn magic_load_buffers(cookie: *const Magic, buffers: *mut *mut c_void, sizes: *mut size_t, nbuffers: size_t) -> c_int;
+    }
+}
+
 
 /// Bitmask flags which control `libmagic` behaviour
 pub mod flags {
+    // bitflags 0.7 expands to the deprecated `try!` macro
+    #![allow(deprecated)]
+
     use libc::c_int;
 
     bitflags! {
@@ -118,13 +154,24 @@ pub mod flags {
             #[doc = "Don't check for cdf files"]
             const NO_CHECK_CDF      = 0x040000,
 
+            #[doc = "Don't check for CSV files"]
+            const NO_CHECK_CSV      = 0x080000,
+
             #[doc = "Don't check tokens"]
             const NO_CHECK_TOKENS   = 0x100000,
 
             #[doc = "Don't check text encodings"]
             const NO_CHECK_ENCODING = 0x200000,
 
-            #[doc = "No built-in tests; only consult the magic file"]
+            #[doc = "Don't check for JSON files"]
+            const NO_CHECK_JSON     = 0x400000,
+
+            #[doc = "Don't check for SIMH tape files"]
+            const NO_CHECK_SIMH     = 0x800000,
+
+            #[doc = "No built-in tests of `libmagic` before 5.35; only consult the magic file"]
+            #[doc = ""]
+            #[doc = "Newer versions have more built-in tests, see `CookieFlags::no_check_builtin`."]
             const NO_CHECK_BUILTIN  = NO_CHECK_COMPRESS.bits
                                      | NO_CHECK_TAR.bits
                                      | NO_CHECK_APPTYPE.bits
@@ -132,10 +179,128 @@ pub mod flags {
                                      | NO_CHECK_TEXT.bits
                                      | NO_CHECK_CDF.bits
                                      | NO_CHECK_TOKENS.bits
-                                     | NO_CHECK_ENCODING.bits
-                                     | 0,
+                                     | NO_CHECK_ENCODING.bits,
+
+            #[doc = "Return a slash-separated list of extensions"]
+            const EXTENSION         = 0x1000000,
+
+            #[doc = "Check inside compressed files but not report compression"]
+            const COMPRESS_TRANSP   = 0x2000000,
 	    }
     }
+
+    /// Readable shorthands, e.g. `CookieFlags::mime().compressed().follow_symlinks()`
+    impl CookieFlags {
+        /// Describe the contents in prose, which is what `NONE` does
+        pub fn description() -> CookieFlags { NONE }
+
+        /// Return MIME type and encoding, e.g. `text/plain; charset=us-ascii`
+        pub fn mime() -> CookieFlags { MIME }
+
+        /// Return only the MIME type, e.g. `text/plain`
+        pub fn mime_type() -> CookieFlags { MIME_TYPE }
+
+        /// Return only the MIME encoding, e.g. `us-ascii`
+        pub fn mime_encoding() -> CookieFlags { MIME_ENCODING }
+
+        /// Return the Apple creator and type
+        pub fn apple() -> CookieFlags { APPLE }
+
+        /// Return the usual filename extensions
+        pub fn extension() -> CookieFlags { EXTENSION }
+
+        /// Also look inside compressed files
+        pub fn compressed(self) -> CookieFlags { self | COMPRESS }
+
+        /// Look inside compressed files without reporting the compression
+        pub fn compressed_transparently(self) -> CookieFlags { self | COMPRESS | COMPRESS_TRANSP }
+
+        /// Examine what symbolic links point to
+        pub fn follow_symlinks(self) -> CookieFlags { self | SYMLINK }
+
+        /// Read the contents of block and character devices
+        pub fn devices(self) -> CookieFlags { self | DEVICES }
+
+        /// Return all matches, not just the first
+        pub fn all_matches(self) -> CookieFlags { self | CONTINUE }
+
+        /// Keep unprintable characters instead of escaping them
+        pub fn raw(self) -> CookieFlags { self | RAW }
+
+        /// Restore the access time of examined files
+        pub fn preserve_atime(self) -> CookieFlags { self | PRESERVE_ATIME }
+
+        /// Skip all built-in tests the linked `libmagic` has, unlike the fixed `NO_CHECK_BUILTIN`
+        pub fn no_check_builtin() -> CookieFlags {
+            let current = super::LibmagicVersion::current();
+            super::versioned_flags().iter()
+                .filter(|&&(flag, since)| [NO_CHECK_CSV, NO_CHECK_JSON, NO_CHECK_SIMH].contains(&flag) && current >= since)
+                .fold(NO_CHECK_BUILTIN, |flags, &(flag, _)| flags | flag)
+        }
+    }
+
+    // Single flags, in the order `Display` lists them
+    const NAMES: [(&str, CookieFlags); 27] = [
+        ("debug", DEBUG), ("symlink", SYMLINK), ("compress", COMPRESS), ("devices", DEVICES),
+        ("mime_type", MIME_TYPE), ("continue", CONTINUE), ("check", CHECK), ("preserve_atime", PRESERVE_ATIME),
+        ("raw", RAW), ("error", ERROR), ("mime_encoding", MIME_ENCODING), ("apple", APPLE),
+        ("no_check_compress", NO_CHECK_COMPRESS), ("no_check_tar", NO_CHECK_TAR), ("no_check_soft", NO_CHECK_SOFT),
+        ("no_check_apptype", NO_CHECK_APPTYPE), ("no_check_elf", NO_CHECK_ELF), ("no_check_text", NO_CHECK_TEXT),
+        ("no_check_cdf", NO_CHECK_CDF), ("no_check_csv", NO_CHECK_CSV), ("no_check_tokens", NO_CHECK_TOKENS),
+        ("no_check_encoding", NO_CHECK_ENCODING), ("no_check_json", NO_CHECK_JSON), ("no_check_simh", NO_CHECK_SIMH),
+        ("extension", EXTENSION), ("compress_transp", COMPRESS_TRANSP), ("none", NONE),
+    ];
+
+    /// An unknown name in a flag list, see `CookieFlags::from_str`
+    #[derive(PartialEq, Eq, Clone, Debug)]
+    pub struct ParseFlagsError {
+        pub name: String,
+    }
+
+    impl ::std::error::Error for ParseFlagsError {}
+
+    impl ::std::fmt::Display for ParseFlagsError {
+        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+            write!(f, "unknown flag `{}'", self.name)
+        }
+    }
+
+    /// Parses comma-separated, case-insensitive flag names like `"mime,compress,symlink"`
+    ///
+    /// Besides the names `Display` uses, `mime` and `no_check_builtin` stand
+    /// for the combined flags. An empty string means `NONE`.
+    impl ::std::str::FromStr for CookieFlags {
+        type Err = ParseFlagsError;
+
+        fn from_str(s: &str) -> Result<CookieFlags, ParseFlagsError> {
+            let mut flags = NONE;
+            for name in s.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+                let lowercase = name.to_lowercase();
+                let flag = match lowercase.as_str() {
+                    "mime" => MIME,
+                    "no_check_builtin" => NO_CHECK_BUILTIN,
+                    lowercase => match NAMES.iter().find(|&&(known, _)| known == lowercase) {
+                        Some(&(_, flag)) => flag,
+                        None => return Err(ParseFlagsError{name: name.to_string(),}),
+                    },
+                };
+                flags |= flag;
+            }
+            Ok(flags)
+        }
+    }
+
+    /// Lists the set flags by name, e.g. `mime_type,compress`, or `none`
+    impl ::std::fmt::Display for CookieFlags {
+        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+            let names: Vec<&str> = NAMES.iter().filter(|&&(_, flag)| !flag.is_empty() && self.contains(flag)).map(|&(name, _)| name).collect();
+            if names.is_empty() {
+                write!(f, "none")
+            } else {
+                write!(f, "{}", names.join(","))
+            }
+        }
+    }
 }
 
 
@@ -150,12 +315,162 @@ pub fn version() -> &'static str {
 }
 
 
-fn db_filenames<P: AsRef<Path>>(filenames: &[P]) -> *const c_char {
-    match filenames.len() {
-        0 => ptr::null(),
-        // FIXME: This is just plain wrong. I'm surprised it works at all..
-        1 => CString::new(filenames[0].as_ref().to_str().unwrap()).unwrap().as_ptr(),
-        _ => unimplemented!(),
+/// Looks up `magic_getflags` at runtime, since `libmagic` before 5.33 does not have it
+fn magic_getflags_fn() -> Option<unsafe extern "C" fn(*const self::ffi::Magic) -> c_int> {
+    let symbol = unsafe { libc::dlsym(libc::RTLD_DEFAULT, b"magic_getflags\0".as_ptr() as *const c_char) };
+    if symbol.is_null() {
+        None
+    } else {
+        Some(unsafe { std::mem::transmute::<*mut libc::c_void, unsafe extern "C" fn(*const self::ffi::Magic) -> c_int>(symbol) })
+    }
+}
+
+/// Returns the version of the linked `libmagic` as reported by `magic_version`, e.g. `544` for 5.44
+pub fn libmagic_version() -> i32 {
+    unsafe { self::ffi::magic_version() }
+}
+
+/// Version of `libmagic`, ordered so that features can be gated on it
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub struct LibmagicVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl LibmagicVersion {
+    /// Returns the version of the linked `libmagic`
+    pub fn current() -> LibmagicVersion {
+        LibmagicVersion::from_int(libmagic_version())
+    }
+
+    /// Splits a `magic_version` value like `544` into 5.44
+    pub fn from_int(version: i32) -> LibmagicVersion {
+        let version = version.max(0) as u32;
+        LibmagicVersion{major: version / 100, minor: version % 100,}
+    }
+}
+
+impl Display for LibmagicVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{:02}", self.major, self.minor)
+    }
+}
+
+// Flags that older `libmagic` versions silently ignore, with the version that introduced them
+fn versioned_flags() -> [(self::flags::CookieFlags, LibmagicVersion); 4] {
+    [
+        (self::flags::COMPRESS_TRANSP, LibmagicVersion{major: 5, minor: 32,}),
+        (self::flags::NO_CHECK_CSV, LibmagicVersion{major: 5, minor: 35,}),
+        (self::flags::NO_CHECK_JSON, LibmagicVersion{major: 5, minor: 35,}),
+        (self::flags::NO_CHECK_SIMH, LibmagicVersion{major: 5, minor: 45,}),
+    ]
+}
+
+fn check_flags_supported(flags: self::flags::CookieFlags) -> Result<(), MagicError> {
+    let current = LibmagicVersion::current();
+    for &(flag, since) in &versioned_flags() {
+        if flags.contains(flag) && current < since {
+            return Err(MagicError{desc: format!("flag {:?} requires libmagic {} or newer, linked version is {}", flag, since, current), errno: None, kind: MagicErrorKind::Other,});
+        }
+    }
+    check_flag_conflicts(flags)
+}
+
+// `libmagic` produces only one kind of output, so asking for several gives mixed-up results
+fn check_flag_conflicts(flags: self::flags::CookieFlags) -> Result<(), MagicError> {
+    use self::flags::{MIME, APPLE, EXTENSION, CONTINUE};
+
+    let outputs = [("MIME", MIME), ("APPLE", APPLE), ("EXTENSION", EXTENSION)];
+    let requested: Vec<&str> = outputs.iter().filter(|&&(_, output)| flags.intersects(output)).map(|&(name, _)| name).collect();
+    if requested.len() > 1 {
+        return Err(MagicError{desc: format!("flags {} cannot be combined", requested.join(" and ")), errno: None, kind: MagicErrorKind::Other,});
+    }
+    if flags.contains(CONTINUE) && flags.intersects(MIME | APPLE) {
+        return Err(MagicError{desc: format!("flag CONTINUE cannot be combined with {}", requested[0]), errno: None, kind: MagicErrorKind::Other,});
+    }
+    Ok(())
+}
+
+// Paths are passed on as raw bytes, so they need not be valid UTF-8
+fn path_to_cstring(path: &Path) -> Result<CString, MagicError> {
+    use std::os::unix::ffi::OsStrExt;
+
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| MagicError{desc: format!("path `{}' contains a NUL byte", path.display()), errno: None, kind: MagicErrorKind::InvalidPath,})
+}
+
+// `libmagic` splits the list on `:` and has no way to escape it, so such paths are rejected
+fn db_filenames<P: AsRef<Path>>(filenames: &[P]) -> Result<Option<CString>, MagicError> {
+    if filenames.is_empty() {
+        return Ok(None);
+    }
+    let mut joined = Vec::new();
+    for filename in filenames {
+        let filename = filename.as_ref();
+        let bytes = path_to_cstring(filename)?.into_bytes();
+        if bytes.contains(&b':') {
+            return Err(MagicError{desc: format!("path `{}' contains the database separator `:'", filename.display()), errno: None, kind: MagicErrorKind::InvalidPath,});
+        }
+        if !joined.is_empty() {
+            joined.push(b':');
+        }
+        joined.extend_from_slice(&bytes);
+    }
+    CString::new(joined).map(Some).map_err(|_| MagicError{desc: "database list contains a NUL byte".to_string(), errno: None, kind: MagicErrorKind::Other,})
+}
+
+// The name `libmagic` gives the compiled database, in the working directory
+fn compiled_name(filename: &Path) -> Option<PathBuf> {
+    let name = Path::new(filename.file_name()?);
+    if name.extension().is_some_and(|extension| extension == "mgc") {
+        Some(name.to_path_buf())
+    } else {
+        let mut name = name.as_os_str().to_os_string();
+        name.push(".mgc");
+        Some(PathBuf::from(name))
+    }
+}
+
+// Creates a new file only the current user can access in the temporary directory, never reusing an existing path
+fn create_private_file(prefix: &str) -> io::Result<(PathBuf, File)> {
+    use std::os::unix::fs::OpenOptionsExt;
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut last = io::Error::from(io::ErrorKind::AlreadyExists);
+    for _ in 0..16 {
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        let path = std::env::temp_dir().join(format!(
+            "{}-{}-{}-{}", prefix, std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst), nanos));
+        match std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => last = e,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last)
+}
+
+fn db_filenames_ptr(db_filenames: &Option<CString>) -> *const c_char {
+    match *db_filenames {
+        Some(ref filenames) => filenames.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+
+fn special_file_failure(filename: &Path) -> MagicError {
+    use std::os::unix::fs::FileTypeExt;
+
+    let special = match std::fs::metadata(filename) {
+        Ok(metadata) => {
+            let file_type = metadata.file_type();
+            file_type.is_fifo() || file_type.is_socket() || file_type.is_char_device() || file_type.is_block_device()
+        },
+        Err(_) => false,
+    };
+    if special {
+        MagicError{desc: format!("cannot read special file `{}' without blocking", filename.display()), errno: Some(libc::EAGAIN), kind: MagicErrorKind::SpecialFile,}
+    } else {
+        MagicError{desc: "unknown error".to_string(), errno: None, kind: MagicErrorKind::Other,}
     }
 }
 
@@ -166,6 +481,44 @@ fn db_filenames<P: AsRef<Path>>(filenames: &[P]) -> *const c_char {
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct MagicError {
     pub desc: String,
+    /// The `errno` `libmagic` reported along with the error, if any
+    pub errno: Option<i32>,
+    pub kind: MagicErrorKind,
+}
+
+/// What kind of failure a `MagicError` is about
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MagicErrorKind {
+    /// A path that cannot be passed to `libmagic`, e.g. one with an interior NUL byte
+    InvalidPath,
+    /// A FIFO, socket or device that cannot be read without blocking, see `Cookie::file`
+    SpecialFile,
+    /// Any other failure, usually reported by `libmagic` itself
+    Other,
+}
+
+impl MagicError {
+    /// Returns the `errno` as an `io::Error`, e.g. to match on its `io::ErrorKind`
+    pub fn io_error(&self) -> Option<io::Error> {
+        self.errno.map(io::Error::from_raw_os_error)
+    }
+
+    /// Returns the `io::ErrorKind` of the `errno`, if there is one
+    pub fn io_error_kind(&self) -> Option<io::ErrorKind> {
+        self.io_error().map(|e| e.kind())
+    }
+}
+
+impl From<MagicError> for io::Error {
+    /// Keeps the `io::ErrorKind` of the `errno` and uses the description as message
+    fn from(e: MagicError) -> io::Error {
+        let kind = match e.kind {
+            MagicErrorKind::InvalidPath => io::ErrorKind::InvalidInput,
+            MagicErrorKind::SpecialFile => io::ErrorKind::WouldBlock,
+            MagicErrorKind::Other => e.io_error_kind().unwrap_or(io::ErrorKind::Other),
+        };
+        io::Error::new(kind, e.desc)
+    }
 }
 
 impl error::Error for MagicError {
@@ -181,212 +534,2868 @@ impl Display for MagicError {
 }
 
 
-pub struct Cookie {
-    cookie: *const self::ffi::Magic,
+/// A problem with a rule that `libmagic` reported while compiling, see `Cookie::compile_detailed`
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct CompileError {
+    pub path: PathBuf,
+    pub line: u32,
+    pub message: String,
 }
 
-impl Drop for Cookie {
-    fn drop(&mut self) { unsafe { self::ffi::magic_close(self.cookie) } }
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}, {}: {}", self.path.display(), self.line, self.message)
+    }
 }
 
-impl Cookie {
-    fn last_error(&self) -> Option<MagicError> {
-        let cookie = self.cookie;
+/// Why `Cookie::compile_detailed` failed
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct CompileFailure {
+    /// The error `compile` returns
+    pub error: MagicError,
+    /// The problems found in individual rules, possibly none
+    pub errors: Vec<CompileError>,
+}
 
-        unsafe {
-            let e = self::ffi::magic_error(cookie);
-            if e.is_null() {
-                None
-            } else {
-                let slice = CStr::from_ptr(e).to_bytes();
-                Some(self::MagicError{desc: str::from_utf8(slice).unwrap().to_string(),})
-            }
+impl error::Error for CompileFailure {}
+
+impl Display for CompileFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)?;
+        for e in &self.errors {
+            write!(f, "\n{}", e)?;
         }
+        Ok(())
     }
+}
 
-    fn magic_failure(&self) -> MagicError {
-        match self.last_error() {
-            Some(e) => e,
-            None => self::MagicError{desc: "unknown error".to_string(),}
+// Picks the lines like "magic/foo, 12: Warning: type `bar' invalid" out of `libmagic`'s output
+fn parse_compile_errors(output: &str) -> Vec<CompileError> {
+    output.lines().filter_map(|line| {
+        let (location, message) = line.split_once(": Warning: ")?;
+        let (path, number) = location.rsplit_once(", ")?;
+        Some(CompileError{path: PathBuf::from(path), line: number.parse().ok()?, message: message.to_string(),})
+    }).collect()
+}
+
+
+/// An entry of a database listing, see `Cookie::entries`
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct MagicEntry {
+    /// Strength used to order the entries, higher is tried first
+    pub strength: u32,
+    /// Line of the entry in its source file
+    pub line: u32,
+    pub message: String,
+    pub mime: Option<String>,
+    /// Whether the entry is applied to text rather than binary data
+    pub text: bool,
+}
+
+// Parses lines like `Strength = 110@1: PNG image data [image/png]` printed by `magic_list`
+fn parse_listing(listing: &str) -> Vec<MagicEntry> {
+    let mut entries = Vec::new();
+    let mut text = false;
+    for line in listing.lines() {
+        if line.starts_with("Binary patterns:") {
+            text = false;
+        } else if line.starts_with("Text patterns:") {
+            text = true;
+        } else if let Some(rest) = line.strip_prefix("Strength =") {
+            let (position, rest) = match rest.find(": ") {
+                Some(i) => (&rest[..i], &rest[i + 2..]),
+                None => continue,
+            };
+            let mut position = position.trim().splitn(2, '@').map(|n| n.parse::<u32>());
+            let (strength, line) = match (position.next(), position.next()) {
+                (Some(Ok(strength)), Some(Ok(line))) => (strength, line),
+                _ => continue,
+            };
+            let (message, mime) = match rest.rfind(" [") {
+                Some(i) if rest.ends_with(']') => (&rest[..i], &rest[i + 2..rest.len() - 1]),
+                _ => (rest, ""),
+            };
+            entries.push(MagicEntry{
+                strength,
+                line,
+                message: message.to_string(),
+                mime: if mime.is_empty() { None } else { Some(mime.to_string()) },
+                text,
+            });
         }
     }
+    entries
+}
 
-    pub fn file<P: AsRef<Path>>(&self, filename: P) -> Result<String, MagicError> {
-        let cookie = self.cookie;
-        let f = CString::new(filename.as_ref().to_str().unwrap()).unwrap().as_ptr();
-        unsafe {
-            let str = self::ffi::magic_file(cookie, f);
-            if str.is_null() {
-                Err(self.magic_failure())
-            } else {
-                let slice = CStr::from_ptr(str).to_bytes();
-                Ok(str::from_utf8(slice).unwrap().to_string())
-            }
+// What `libmagic` returns when nothing matched, depending on the flags
+fn is_no_match(description: &str) -> bool {
+    let mime = description.split(';').next().unwrap_or(description);
+    ["data", "application/octet-stream", "???", "UNKNUNKN"].contains(&mime)
+}
+
+// `libmagic` separates the matches with a newline and `- `, escaped unless `flags::RAW` is set
+fn parse_matches(description: &str) -> Vec<Match> {
+    description.split("\\012- ").flat_map(|part| part.split("\n- "))
+        .map(|part| part.trim())
+        .filter(|part| !part.is_empty())
+        .map(|part| Match{description: part.to_string(),})
+        .collect()
+}
+
+// `libmagic` returns `???` if no extension is known
+fn parse_extensions(extensions: &str) -> Vec<String> {
+    // With `flags::COMPRESS` those of the compressed data come first, then those of the container in parentheses
+    let extensions = extensions.split(" (").next().unwrap_or("");
+    extensions.split('/').filter(|extension| !extension.is_empty() && *extension != "???").map(|extension| extension.to_string()).collect()
+}
+
+// Runs `f` with stderr captured as for the warning handler
+fn capture_warnings<T, F: FnOnce() -> T>(f: F) -> Result<(T, String), MagicError> {
+    let (result, output) = capture_fd(libc::STDERR_FILENO, f)
+        .map_err(|e| MagicError{desc: format!("cannot capture warnings ({})", e), errno: e.raw_os_error(), kind: MagicErrorKind::Other,})?;
+    Ok((result, String::from_utf8_lossy(&output).into_owned()))
+}
+
+/// Runs `f` with `fd` redirected into a pipe and returns its result together with what was written to `fd`
+///
+/// Redirection affects the whole process, so only one capture runs at a time.
+fn capture_fd<T, F: FnOnce() -> T>(fd: c_int, f: F) -> io::Result<(T, Vec<u8>)> {
+    use std::os::unix::io::FromRawFd;
+    use std::sync::Mutex;
+
+    static CAPTURE: Mutex<()> = Mutex::new(());
+    let _guard = CAPTURE.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pipe = [0 as c_int; 2];
+    unsafe {
+        libc::fflush(ptr::null_mut());
+        if libc::pipe(pipe.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
         }
     }
-
-    pub fn buffer(&self, buffer: &[u8]) -> Result<String, MagicError> {
-        let buffer_len = buffer.len() as size_t;
-        let pbuffer = buffer.as_ptr();
+    let mut reader = unsafe { File::from_raw_fd(pipe[0]) };
+    unsafe { libc::fcntl(pipe[0], libc::F_SETFL, libc::fcntl(pipe[0], libc::F_GETFL) | libc::O_NONBLOCK) };
+    let saved = unsafe { libc::dup(fd) };
+    if saved < 0 || unsafe { libc::dup2(pipe[1], fd) } < 0 {
+        let e = io::Error::last_os_error();
         unsafe {
-            let str = self::ffi::magic_buffer(self.cookie, pbuffer, buffer_len);
-            if str.is_null() {
-                Err(self.magic_failure())
-            } else {
-                let slice = CStr::from_ptr(str).to_bytes();
-                Ok(str::from_utf8(slice).unwrap().to_string())
+            libc::close(pipe[1]);
+            if saved >= 0 {
+                libc::close(saved);
             }
         }
+        return Err(e);
     }
-
-    pub fn error(&self) -> Option<String> {
-        unsafe {
-            let str = self::ffi::magic_error(self.cookie);
-            if str.is_null() {
-                None
-            } else {
-                let slice = CStr::from_ptr(str).to_bytes();
-                Some(str::from_utf8(slice).unwrap().to_string())
+    unsafe { libc::close(pipe[1]) };
+
+    // Read concurrently so that `f` cannot block on a full pipe. Child
+    // processes started meanwhile inherit the write end, so rather than
+    // waiting for EOF, reading stops once `fd` is restored and drained.
+    let restored = Arc::new(AtomicBool::new(false));
+    let collector = {
+        let restored = restored.clone();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let mut chunk = [0; 4096];
+            loop {
+                let finished = restored.load(Ordering::SeqCst);
+                match reader.read(&mut chunk) {
+                    Ok(0) => return Ok(output),
+                    Ok(n) => output.extend_from_slice(&chunk[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if finished {
+                            return Ok(output);
+                        }
+                        let mut pollfd = libc::pollfd{fd: reader.as_raw_fd(), events: libc::POLLIN, revents: 0,};
+                        unsafe { libc::poll(&mut pollfd, 1, 10) };
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    Err(e) => return Err(e),
+                }
             }
-        }
+        })
+    };
+    let result = f();
+    unsafe {
+        libc::fflush(ptr::null_mut());
+        libc::dup2(saved, fd);
+        libc::close(saved);
     }
+    restored.store(true, Ordering::SeqCst);
 
-    pub fn set_flags(&self, flags: self::flags::CookieFlags) -> bool {
-        unsafe {
-            self::ffi::magic_setflags(self.cookie, flags.bits()) != -1
-        }
-    }
+    let output = collector.join().unwrap_or_else(|_| Err(io::Error::other("capture thread panicked")))?;
+    Ok((result, output))
+}
 
-    // TODO: check, compile, list and load mostly do the same, refactor!
-    // TODO: ^ also needs to implement multiple databases, possibly waiting for the Path reform
 
-    pub fn check<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<(), MagicError> {
-        let cookie = self.cookie;
-        let db_filenames = db_filenames(filenames);
-        let ret;
+/// Tunable limits of `libmagic`, see `Cookie::set_param`
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Param {
+    /// Recursion limit for indirect magic
+    IndirMax,
+    /// Use count limit for name/use magic
+    NameMax,
+    /// Maximum number of ELF program sections processed
+    ElfPhnumMax,
+    /// Maximum number of ELF sections processed
+    ElfShnumMax,
+    /// Maximum number of ELF notes processed
+    ElfNotesMax,
+    /// Length limit for regex searches
+    RegexMax,
+    /// Maximum number of bytes to read from a file
+    BytesMax,
+    /// Maximum number of bytes to scan for encoding detection
+    EncodingMax,
+}
 
-        unsafe {
-            ret = self::ffi::magic_check(cookie, db_filenames);
-        }
-        if 0 == ret { Ok(()) } else { Err(self.magic_failure()) }
+impl Param {
+    fn all() -> [Param; 8] {
+        [Param::IndirMax, Param::NameMax, Param::ElfPhnumMax, Param::ElfShnumMax,
+         Param::ElfNotesMax, Param::RegexMax, Param::BytesMax, Param::EncodingMax]
     }
 
-    pub fn compile<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<(), MagicError> {
-        let cookie = self.cookie;
-        let db_filenames = db_filenames(filenames);
-        let ret;
-
-        unsafe {
-            ret = self::ffi::magic_compile(cookie, db_filenames);
+    fn to_c_int(self) -> c_int {
+        match self {
+            Param::IndirMax => 0,
+            Param::NameMax => 1,
+            Param::ElfPhnumMax => 2,
+            Param::ElfShnumMax => 3,
+            Param::ElfNotesMax => 4,
+            Param::RegexMax => 5,
+            Param::BytesMax => 6,
+            Param::EncodingMax => 7,
         }
-        if 0 == ret { Ok(()) } else { Err(self.magic_failure()) }
     }
+}
 
-    pub fn list<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<(), MagicError> {
-        let cookie = self.cookie;
-        let db_filenames = db_filenames(filenames);
-        let ret;
 
-        unsafe {
-            ret = self::ffi::magic_list(cookie, db_filenames);
-        }
-        if 0 == ret { Ok(()) } else { Err(self.magic_failure()) }
+/// How `Cookie` converts the bytes returned by `libmagic` into a `String`
+///
+/// Descriptions can contain non-UTF-8 bytes copied from the examined data,
+/// especially with `flags::RAW` set. Use `Cookie::file_bytes` or
+/// `Cookie::buffer_bytes` to get the unmodified bytes instead.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Decoding {
+    /// Return a `MagicError` for invalid UTF-8
+    Strict,
+    /// Replace invalid UTF-8 sequences with `U+FFFD REPLACEMENT CHARACTER`
+    Lossy,
+}
+
+
+/// How often `Cookie::file` retries after a transient I/O error
+///
+/// `EINTR`, `EAGAIN`, `EIO` and `ETIMEDOUT` are considered transient, as
+/// they are commonly caused by signals or short network filesystem outages.
+/// The delay before each retry starts at `backoff` and doubles each time.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Retry {
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Retry {
+    /// Never retry
+    pub fn none() -> Retry {
+        Retry{attempts: 0, backoff: Duration::from_millis(0),}
     }
+}
 
-    pub fn load<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<(), MagicError> {
-        let cookie = self.cookie;
-        let db_filenames = db_filenames(filenames);
-        let ret;
+fn is_transient(errno: c_int) -> bool {
+    errno == libc::EINTR || errno == libc::EAGAIN || errno == libc::EIO || errno == libc::ETIMEDOUT
+}
 
-        unsafe {
-            ret = self::ffi::magic_load(cookie, db_filenames);
-        }
-        if 0 == ret { Ok(()) } else { Err(self.magic_failure()) }
+
+/// The error of the most recent failed `Cookie` operation
+///
+/// `libmagic`'s own error string is only valid until the next call on the
+/// cookie, so this is captured right when an operation fails.
+#[derive(Debug)]
+pub struct LastError {
+    pub message: String,
+    pub errno: Option<io::Error>,
+}
+
+
+/// Everything `Cookie::detect_file` found out about a file
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DetectionResult {
+    pub description: String,
+    pub mime_type: String,
+    pub encoding: String,
+    /// Empty if `libmagic` knows no extension
+    pub extensions: Vec<String>,
+}
+
+
+/// One of the matches `Cookie::file_matches` returns
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Match {
+    pub description: String,
+}
+
+impl Display for Match {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.description)
     }
+}
 
-    pub fn open(flags: self::flags::CookieFlags) -> Result<Cookie, MagicError> {
-        let cookie;
-        unsafe {
-            cookie = self::ffi::magic_open((flags | self::flags::ERROR).bits());
+
+/// The Apple creator and type codes reported with `flags::APPLE`
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct AppleType {
+    pub creator: [u8; 4],
+    pub kind: [u8; 4],
+}
+
+impl AppleType {
+    /// Parses the 8-byte `libmagic` result, e.g. `b"????PNGf"`
+    ///
+    /// Returns `None` for `libmagic`'s unknown marker `UNKNUNKN` and for
+    /// anything that is not exactly 8 bytes long, apart from the type of
+    /// the container `flags::COMPRESS` appends in parentheses.
+    pub fn parse(apple: &[u8]) -> Option<AppleType> {
+        // With `flags::COMPRESS` the type of the container follows in parentheses
+        let apple = if apple.len() > 8 && apple[8..].starts_with(b" (") { &apple[..8] } else { apple };
+        if apple.len() != 8 || apple == b"UNKNUNKN" {
+            return None;
         }
-        if cookie.is_null() { Err(self::MagicError{desc: "errno".to_string(),}) } else { Ok(Cookie {cookie: cookie,}) }
+        let mut creator = [0; 4];
+        let mut kind = [0; 4];
+        creator.copy_from_slice(&apple[..4]);
+        kind.copy_from_slice(&apple[4..]);
+        Some(AppleType{creator, kind,})
     }
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate regex;
 
-    use super::Cookie;
-	use super::flags;
+/// Owns a `magic_t`
+///
+/// The pointer is never null, and it is closed exactly once, in `drop`.
+/// Nothing else may call `magic_close` on it.
+struct RawCookie(*const self::ffi::Magic);
 
-    // Using relative paths to test files should be fine, since cargo doc
-    // http://doc.crates.io/build-script.html#inputs-to-the-build-script
-    // states that cwd == CARGO_MANIFEST_DIR
+impl RawCookie {
+    fn open(flags: c_int) -> Option<RawCookie> {
+        let cookie = unsafe { self::ffi::magic_open(flags) };
+        if cookie.is_null() { None } else { Some(RawCookie(cookie)) }
+    }
 
-    #[test]
-    fn file() {
-        let cookie = Cookie::open(flags::NONE).ok().unwrap();
-        assert!(cookie.load(&vec!["data/tests/db-images-png"]).is_ok());
+    fn as_ptr(&self) -> *const self::ffi::Magic {
+        self.0
+    }
 
-        let path = "data/tests/rust-logo-128x128-blk.png";
+    // Gives up ownership without closing the cookie
+    fn into_raw(self) -> *const self::ffi::Magic {
+        let cookie = self.0;
+        std::mem::forget(self);
+        cookie
+    }
+}
+
+impl Drop for RawCookie {
+    fn drop(&mut self) { unsafe { self::ffi::magic_close(self.0) } }
+}
+
+// A `magic_t` has no affinity to the thread that opened it, it only must not be
+// used by several threads at once. Owning it means nobody else uses it, and
+// `Cookie` is not `Sync` because of its `Cell`s, so moving it is fine.
+unsafe impl Send for RawCookie {}
 
-        assert_eq!(cookie.file(&path).ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
 
-        cookie.set_flags(flags::MIME_TYPE);
-        assert_eq!(cookie.file(&path).ok().unwrap(), "image/png");
+/// Input that `Cookie::detect` can classify
+pub trait Detectable {
+    fn detect_with(self, cookie: &Cookie) -> Result<String, MagicError>;
+}
 
-        cookie.set_flags(flags::MIME_TYPE | flags::MIME_ENCODING);
-        assert_eq!(cookie.file(&path).ok().unwrap(), "image/png; charset=binary");
+impl Detectable for &Path {
+    fn detect_with(self, cookie: &Cookie) -> Result<String, MagicError> {
+        cookie.file(self)
     }
+}
 
-    #[test]
-    fn buffer() {
-        let cookie = Cookie::open(flags::NONE).ok().unwrap();
-        assert!(cookie.load(&vec!["data/tests/db-python"].as_slice()).is_ok());
+/// Classifies the file from its current position, without reopening it by path
+impl Detectable for &File {
+    fn detect_with(self, cookie: &Cookie) -> Result<String, MagicError> {
+        cookie.fileobj(self)
+    }
+}
 
-        let s = b"#!/usr/bin/env python\nprint('Hello, world!')";
-        assert_eq!(cookie.buffer(s).ok().unwrap(), "Python script, ASCII text executable");
+impl Detectable for &[u8] {
+    fn detect_with(self, cookie: &Cookie) -> Result<String, MagicError> {
+        cookie.buffer(self)
+    }
+}
 
-        cookie.set_flags(flags::MIME_TYPE);
-        assert_eq!(cookie.buffer(s).ok().unwrap(), "text/x-python");
+/// Reads only as much as needed, see `Cookie::buffer_progressive`
+impl<'a, 'r> Detectable for &'a mut (dyn Read + 'r) {
+    fn detect_with(self, cookie: &Cookie) -> Result<String, MagicError> {
+        cookie.buffer_progressive(self)
     }
+}
 
-    #[test]
-    fn file_error() {
-        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
-        assert!(cookie.load::<&str>(&[]).is_ok());
 
-        let ret = cookie.file("non-existent_file.txt");
-        assert!(ret.is_err());
-        assert_eq!(ret.err().unwrap().desc, "cannot stat `non-existent_file.txt' (No such file or directory)");
+/// Escalation schedule and stopping rule for `Cookie::buffer_progressive_with`
+///
+/// Each prefix length is classified in increasing order until the
+/// description satisfies the `specific` predicate or the input ends.
+pub struct Progressive {
+    prefixes: Vec<usize>,
+    specific: Box<dyn Fn(&str) -> bool>,
+}
+
+impl Progressive {
+    /// Classifies prefixes of the given lengths, which should be increasing
+    pub fn prefixes(mut self, prefixes: Vec<usize>) -> Progressive {
+        self.prefixes = prefixes;
+        self
     }
 
-    #[test]
-    fn load_default() {
-        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
-        assert!(cookie.load::<&str>(&[]).is_ok());
+    /// Stops reading once `specific` returns `true` for a description
+    pub fn specific<F: Fn(&str) -> bool + 'static>(mut self, specific: F) -> Progressive {
+        self.specific = Box::new(specific);
+        self
     }
+}
 
-    #[test]
-    fn load_one() {
-        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
-        assert!(cookie.load(&vec![
-                                    "data/tests/db-images-png"
-                                ]).is_ok());
+/// 4 KiB, 64 KiB and 1 MiB, until the result is not `data` or its equivalent for the flags, see `Cookie::file_match`
+impl Default for Progressive {
+    fn default() -> Progressive {
+        Progressive{
+            prefixes: vec![4 << 10, 64 << 10, 1 << 20],
+            specific: Box::new(|description| !is_no_match(description)),
+        }
+    }
+}
+
+
+type WarningHandler = Box<dyn Fn(&str) + Send>;
+
+
+/// A `libmagic` cookie holding flags and loaded databases
+///
+/// It can be sent to another thread, but not used from several at once;
+/// see `SharedCookie` for that.
+pub struct Cookie {
+    cookie: RawCookie,
+    decoding: Cell<Decoding>,
+    retry: Cell<Retry>,
+    last_error: RefCell<Option<LastError>>,
+    recompile_stale: Cell<bool>,
+    // The flags as set through this wrapper, without the bits `libmagic` sets
+    // itself; temporary flag changes restore these, and `flags()` falls back
+    // to them when `magic_getflags` is missing
+    flags: Cell<self::flags::CookieFlags>,
+    // `libmagic` uses the memory passed to `magic_load_buffers` in place
+    buffers: RefCell<Vec<Vec<u8>>>,
+    databases: RefCell<Databases>,
+    warning_handler: RefCell<Option<WarningHandler>>,
+    // Set while stderr is redirected, since captures cannot be nested
+    capturing_warnings: Cell<bool>,
+}
+
+/// What a `Cookie` loaded last, as the `databases` of its `Debug` output
+#[derive(Clone)]
+enum Databases {
+    None,
+    Default,
+    Files(Vec<PathBuf>),
+    Buffers(usize),
+    Overlay(Vec<PathBuf>, rules::Overlay),
+}
+
+impl std::fmt::Debug for Databases {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Databases::None => f.write_str("none"),
+            Databases::Default => f.write_str("default"),
+            Databases::Files(ref filenames) => f.debug_list().entries(filenames).finish(),
+            Databases::Buffers(count) => write!(f, "{} buffer(s)", count),
+            Databases::Overlay(ref filenames, ref overlay) => write!(f, "{:?} with {:?}", filenames, overlay),
+        }
+    }
+}
+
+impl std::fmt::Debug for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Cookie")
+            .field("flags", &self.flags.get())
+            .field("databases", &*self.databases.borrow())
+            .finish()
+    }
+}
+
+impl Cookie {
+    fn pending_error(&self) -> Option<MagicError> {
+        let cookie = self.cookie.as_ptr();
+
+        unsafe {
+            let e = self::ffi::magic_error(cookie);
+            if e.is_null() {
+                None
+            } else {
+                let slice = CStr::from_ptr(e).to_bytes();
+                Some(self::MagicError{desc: String::from_utf8_lossy(slice).into_owned(), errno: self.errno(), kind: MagicErrorKind::Other,})
+            }
+        }
+    }
+
+    fn magic_failure(&self) -> MagicError {
+        let e = self.pending_error().unwrap_or_else(|| self::MagicError{desc: "unknown error".to_string(), errno: self.errno(), kind: MagicErrorKind::Other,});
+        self.record_failure(e)
+    }
+
+    fn record_failure(&self, e: MagicError) -> MagicError {
+        *self.last_error.borrow_mut() = Some(LastError{message: e.desc.clone(), errno: e.errno.map(io::Error::from_raw_os_error),});
+        e
+    }
+
+    /// Returns the error of the most recent failed operation on this cookie
+    pub fn last_error(&self) -> Option<LastError> {
+        self.last_error.borrow().as_ref().map(|e| LastError{
+            message: e.message.clone(),
+            errno: e.errno.as_ref().and_then(|errno| errno.raw_os_error()).map(io::Error::from_raw_os_error),
+        })
+    }
+
+    fn decode(&self, bytes: Vec<u8>) -> Result<String, MagicError> {
+        match self.decoding.get() {
+            Decoding::Strict => String::from_utf8(bytes).map_err(|e| {
+                self::MagicError{desc: format!("libmagic result is not valid UTF-8: {}", e), errno: None, kind: MagicErrorKind::Other,}
+            }),
+            Decoding::Lossy => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+        }
+    }
+
+    /// Returns a textual description of the contents of the `filename`
+    ///
+    /// `libmagic` opens files without blocking, so a FIFO without a writer
+    /// or a similar special file (when `flags::DEVICES` is set) fails
+    /// right away instead of hanging the caller.
+    pub fn file<P: AsRef<Path>>(&self, filename: P) -> Result<String, MagicError> {
+        self.file_bytes(filename).and_then(|bytes| self.decode(bytes))
+    }
+
+    /// Like `file`, but returns `None` if no magic matched instead of `libmagic`'s fallback
+    ///
+    /// The fallback is `data`, or e.g. `application/octet-stream` with the
+    /// MIME flags. An `Err` then always means the detection itself failed.
+    pub fn file_match<P: AsRef<Path>>(&self, filename: P) -> Result<Option<String>, MagicError> {
+        self.file(filename).map(|description| if is_no_match(&description) { None } else { Some(description) })
+    }
+
+    /// Like `file`, but always replaces invalid UTF-8 regardless of `set_decoding`
+    pub fn file_lossy<P: AsRef<Path>>(&self, filename: P) -> Result<String, MagicError> {
+        self.file_bytes(filename).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Like `file`, but returns the raw bytes of the description without any UTF-8 decoding
+    pub fn file_bytes<P: AsRef<Path>>(&self, filename: P) -> Result<Vec<u8>, MagicError> {
+        if self.captures_debug_output() {
+            let filename = filename.as_ref();
+            return self.with_warnings(|| self.file_bytes(filename));
+        }
+        let cookie = self.cookie.as_ptr();
+        let c_filename = path_to_cstring(filename.as_ref())?;
+        let retry = self.retry.get();
+        let mut backoff = retry.backoff;
+        unsafe {
+            let mut str = self::ffi::magic_file(cookie, c_filename.as_ptr());
+            for _ in 0..retry.attempts {
+                if !str.is_null() || !is_transient(self::ffi::magic_errno(cookie)) {
+                    break;
+                }
+                thread::sleep(backoff);
+                backoff *= 2;
+                str = self::ffi::magic_file(cookie, c_filename.as_ptr());
+            }
+            if str.is_null() {
+                match self.pending_error() {
+                    Some(e) => Err(self.record_failure(e)),
+                    None => Err(self.record_failure(special_file_failure(filename.as_ref()))),
+                }
+            } else {
+                Ok(CStr::from_ptr(str).to_bytes().to_vec())
+            }
+        }
+    }
+
+    /// Returns a textual description of the data read from `reader`, reading as little as possible
+    ///
+    /// Uses the default `Progressive` policy, classifying prefixes of 4 KiB,
+    /// 64 KiB and 1 MiB in turn and stopping as soon as the result is more
+    /// specific than a generic answer like `data`.
+    pub fn buffer_progressive<R: Read>(&self, reader: R) -> Result<String, MagicError> {
+        self.buffer_progressive_with(reader, &Progressive::default())
+    }
+
+    /// Like `buffer_progressive`, but with a custom escalation schedule and stopping rule
+    pub fn buffer_progressive_with<R: Read>(&self, reader: R, policy: &Progressive) -> Result<String, MagicError> {
+        let mut reader = reader;
+        let mut buffer = Vec::new();
+        let mut result = None;
+        for &prefix in policy.prefixes.iter().filter(|&&prefix| prefix > 0) {
+            if prefix <= buffer.len() {
+                continue;
+            }
+            let wanted = (prefix - buffer.len()) as u64;
+            let read = (&mut reader).take(wanted).read_to_end(&mut buffer)
+                .map_err(|e| MagicError{desc: format!("cannot read input ({})", e), errno: None, kind: MagicErrorKind::Other,})?;
+            let description = self.buffer(&buffer)?;
+            let done = (read as u64) < wanted || (policy.specific)(&description);
+            result = Some(description);
+            if done {
+                break;
+            }
+        }
+        match result {
+            Some(description) => Ok(description),
+            None => self.buffer([]),
+        }
+    }
+
+    /// Returns a textual description of the contents of the open file descriptor `fd`
+    ///
+    /// Works for already opened files, pipes and sockets, e.g. ones received
+    /// over fd-passing. Reading starts at the current position and `fd` is
+    /// not closed.
+    pub fn descriptor(&self, fd: RawFd) -> Result<String, MagicError> {
+        self.descriptor_bytes(fd).and_then(|bytes| self.decode(bytes))
+    }
+
+    /// Returns a textual description of the contents of an already opened `file`
+    ///
+    /// Unlike `file`, this does not look up the path again, so the result is
+    /// about exactly the file that was opened. Reading starts at the current
+    /// position.
+    pub fn fileobj(&self, file: &File) -> Result<String, MagicError> {
+        self.descriptor(file.as_raw_fd())
+    }
+
+    /// Like `descriptor`, but returns the raw bytes of the description without any UTF-8 decoding
+    pub fn descriptor_bytes(&self, fd: RawFd) -> Result<Vec<u8>, MagicError> {
+        if self.captures_debug_output() {
+            return self.with_warnings(|| self.descriptor_bytes(fd));
+        }
+        unsafe {
+            let str = self::ffi::magic_descriptor(self.cookie.as_ptr(), fd);
+            if str.is_null() {
+                Err(self.magic_failure())
+            } else {
+                Ok(CStr::from_ptr(str).to_bytes().to_vec())
+            }
+        }
+    }
+
+    /// Returns a textual description of any `Detectable` input
+    ///
+    /// ```
+    /// # extern crate magic;
+    /// # use magic::{Cookie, flags};
+    /// # use std::path::{Path, PathBuf};
+    /// # fn main() {
+    /// let cookie = Cookie::open(flags::NONE).ok().unwrap();
+    /// assert!(cookie.load(&["data/tests/db-python"]).is_ok());
+    ///
+    /// assert!(cookie.detect(Path::new("data/tests/rust-logo-128x128-blk.png")).is_ok());
+    /// assert_eq!(cookie.detect(&b"#!/usr/bin/env python\n"[..]).ok().unwrap(), "Python script, ASCII text executable");
+    /// # }
+    /// ```
+    pub fn detect<D: Detectable>(&self, input: D) -> Result<String, MagicError> {
+        input.detect_with(self)
+    }
+
+    /// Returns description, MIME type, encoding and extensions of the contents of `filename` at once
+    ///
+    /// A regular file is opened only once and each facet is taken from the
+    /// open file like `fileobj` does, by switching flags in between; the
+    /// previous flags are restored afterwards. Other files, including
+    /// symlinks unless `flags::SYMLINK` is set, are examined in place and
+    /// get no extensions.
+    pub fn detect_file<P: AsRef<Path>>(&self, filename: P) -> Result<DetectionResult, MagicError> {
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::fs::OpenOptionsExt;
+        use self::flags::{NONE, EXTENSION, MIME_TYPE, MIME_ENCODING};
+
+        let filename = filename.as_ref();
+        // Like `libmagic` itself, do not wait for a writer when opening a FIFO
+        let mut open_flags = libc::O_NONBLOCK;
+        // Without `flags::SYMLINK`, `libmagic` describes a symlink itself rather than its target
+        if !self.flags.get().contains(self::flags::SYMLINK) {
+            open_flags |= libc::O_NOFOLLOW;
+        }
+        let file = std::fs::OpenOptions::new().read(true).custom_flags(open_flags).open(filename).ok()
+            .and_then(|file| file.metadata().ok().filter(|metadata| metadata.is_file()).map(|metadata| (file, metadata)));
+        let base = self.output_flags(NONE);
+        let describe = |flags| self.with_flags_once(flags, || match file {
+            Some((ref file, _)) => {
+                let mut file = file;
+                file.seek(SeekFrom::Start(0)).map_err(|e| self.record_failure(MagicError{desc: format!("cannot read `{}' ({})", filename.display(), e), errno: e.raw_os_error(), kind: MagicErrorKind::Other,}))?;
+                self.fileobj(file)
+            },
+            None => self.file(filename),
+        });
+        // `libmagic` tries to read e.g. directories with `flags::EXTENSION` and fails
+        let extensions = match file {
+            Some(_) => parse_extensions(&describe(base | EXTENSION)?),
+            None => Vec::new(),
+        };
+        let result = DetectionResult{
+            description: describe(base)?,
+            mime_type: describe(base | MIME_TYPE)?,
+            encoding: describe(base | MIME_ENCODING)?,
+            extensions,
+        };
+
+        // `libmagic` restores the access time only for files it opens itself
+        if let Some((ref file, ref metadata)) = file {
+            if self.flags.get().contains(self::flags::PRESERVE_ATIME) {
+                if let Ok(accessed) = metadata.accessed() {
+                    let _ = file.set_times(std::fs::FileTimes::new().set_accessed(accessed));
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns the MIME type of the contents of `filename`, e.g. `image/png`
+    ///
+    /// Uses `flags::MIME_TYPE` instead of the output flags set for this one
+    /// call and restores them afterwards; flags like `flags::COMPRESS` apply.
+    pub fn file_mime<P: AsRef<Path>>(&self, filename: P) -> Result<String, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::MIME_TYPE), || self.file(filename))
+    }
+
+    /// Returns the MIME encoding of the contents of `filename`, e.g. `us-ascii`, see `file_mime`
+    pub fn file_encoding<P: AsRef<Path>>(&self, filename: P) -> Result<String, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::MIME_ENCODING), || self.file(filename))
+    }
+
+    /// Returns the textual description of the contents of `filename`, regardless of the output flags set
+    pub fn file_description<P: AsRef<Path>>(&self, filename: P) -> Result<String, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::NONE), || self.file(filename))
+    }
+
+    // The flags set, but with `output` instead of the flags that choose the output format
+    fn output_flags(&self, output: self::flags::CookieFlags) -> self::flags::CookieFlags {
+        use self::flags::{MIME, APPLE, EXTENSION, CONTINUE};
+
+        (self.flags.get() - (MIME | APPLE | EXTENSION | CONTINUE)) | output
+    }
+
+    /// Returns all matches for the contents of `filename`, best first
+    ///
+    /// Uses `flags::CONTINUE` instead of the output flags set for this one
+    /// call and restores them afterwards, so the matches are descriptions
+    /// even if e.g. `flags::MIME_TYPE` is set. The last match may be
+    /// `libmagic`'s fallback `data`.
+    pub fn file_matches<P: AsRef<Path>>(&self, filename: P) -> Result<Vec<Match>, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::CONTINUE), || self.file(filename))
+            .map(|description| parse_matches(&description))
+    }
+
+    /// Like `file_matches`, but for the contents of `buffer`
+    pub fn buffer_matches<B: AsRef<[u8]>>(&self, buffer: B) -> Result<Vec<Match>, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::CONTINUE), || self.buffer(buffer))
+            .map(|description| parse_matches(&description))
+    }
+
+    /// Returns the usual filename extensions for the contents of `filename`, e.g. `["jpeg", "jpg"]`
+    ///
+    /// Uses `flags::EXTENSION` instead of the output flags set for this one
+    /// call and restores them afterwards; flags like `flags::COMPRESS` apply.
+    /// The result is empty if `libmagic` knows no extension.
+    pub fn file_extensions<P: AsRef<Path>>(&self, filename: P) -> Result<Vec<String>, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::EXTENSION), || self.file(filename))
+            .map(|extensions| parse_extensions(&extensions))
+    }
+
+    /// Returns the Apple creator and type of the contents of `filename`
+    ///
+    /// Uses `flags::APPLE` instead of the output flags set for this one call
+    /// and restores them afterwards; flags like `flags::COMPRESS` apply.
+    /// The result is `None` if `libmagic` knows no type.
+    pub fn file_apple_type<P: AsRef<Path>>(&self, filename: P) -> Result<Option<AppleType>, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::APPLE), || self.file_bytes(filename))
+            .map(|apple| AppleType::parse(&apple))
+    }
+
+    /// Like `file_apple_type`, but for the contents of `buffer`
+    pub fn buffer_apple_type<B: AsRef<[u8]>>(&self, buffer: B) -> Result<Option<AppleType>, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::APPLE), || self.buffer_bytes(buffer))
+            .map(|apple| AppleType::parse(&apple))
+    }
+
+    /// Runs `f` with `flags` in effect, then restores the previous flags
+    ///
+    /// Fails if `flags` cannot be set, or if the previous flags cannot be
+    /// restored; an error of `f` itself takes precedence.
+    pub fn with_flags<T, F: FnOnce(&Cookie) -> Result<T, MagicError>>(&self, flags: self::flags::CookieFlags, f: F) -> Result<T, MagicError> {
+        self.with_flags_once(flags, || f(self))
+    }
+
+    /// Sets `flags` until the returned guard is dropped, see `ScopedFlags`
+    pub fn scoped_flags(&self, flags: self::flags::CookieFlags) -> Result<ScopedFlags<'_>, MagicError> {
+        let prior = self.flags.get();
+        self.set_flags(flags)?;
+        Ok(ScopedFlags{cookie: self, prior})
+    }
+
+    // Runs `f` with `flags` in effect, then restores the previous flags
+    fn with_flags_once<T, F: FnOnce() -> Result<T, MagicError>>(&self, flags: self::flags::CookieFlags, f: F) -> Result<T, MagicError> {
+        let prior = self.flags.get();
+        self.set_flags(flags)?;
+        let result = f();
+        let restored = self.set_flags(prior);
+        result.and_then(|result| restored.map(|_| result))
+    }
+
+    /// Returns a textual description of the contents of the `buffer`
+    ///
+    /// An empty `buffer` is described as `empty`, or `application/x-empty`
+    /// with `flags::MIME_TYPE`.
+    pub fn buffer<B: AsRef<[u8]>>(&self, buffer: B) -> Result<String, MagicError> {
+        self.buffer_bytes(buffer).and_then(|bytes| self.decode(bytes))
+    }
+
+    /// Like `file_mime`, but for the contents of `buffer`
+    pub fn buffer_mime<B: AsRef<[u8]>>(&self, buffer: B) -> Result<String, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::MIME_TYPE), || self.buffer(buffer))
+    }
+
+    /// Like `file_encoding`, but for the contents of `buffer`
+    pub fn buffer_encoding<B: AsRef<[u8]>>(&self, buffer: B) -> Result<String, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::MIME_ENCODING), || self.buffer(buffer))
+    }
+
+    /// Like `file_description`, but for the contents of `buffer`
+    pub fn buffer_description<B: AsRef<[u8]>>(&self, buffer: B) -> Result<String, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::NONE), || self.buffer(buffer))
+    }
+
+    /// Like `file_extensions`, but for the contents of `buffer`
+    pub fn buffer_extensions<B: AsRef<[u8]>>(&self, buffer: B) -> Result<Vec<String>, MagicError> {
+        self.with_flags_once(self.output_flags(self::flags::EXTENSION), || self.buffer(buffer))
+            .map(|extensions| parse_extensions(&extensions))
+    }
+
+    /// Like `buffer`, but returns `None` if no magic matched, see `file_match`
+    pub fn buffer_match<B: AsRef<[u8]>>(&self, buffer: B) -> Result<Option<String>, MagicError> {
+        self.buffer(buffer).map(|description| if is_no_match(&description) { None } else { Some(description) })
+    }
+
+    /// Like `buffer`, but always replaces invalid UTF-8 regardless of `set_decoding`
+    pub fn buffer_lossy<B: AsRef<[u8]>>(&self, buffer: B) -> Result<String, MagicError> {
+        self.buffer_bytes(buffer).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Like `buffer`, but returns the raw bytes of the description without any UTF-8 decoding
+    pub fn buffer_bytes<B: AsRef<[u8]>>(&self, buffer: B) -> Result<Vec<u8>, MagicError> {
+        let buffer = buffer.as_ref();
+        if self.captures_debug_output() {
+            return self.with_warnings(|| self.buffer_bytes(buffer));
+        }
+        // An empty slice may point anywhere, so hand `libmagic` a real, if unused, byte instead
+        static EMPTY: [u8; 1] = [0];
+        let buffer_len = buffer.len() as size_t;
+        let pbuffer = if buffer.is_empty() { EMPTY.as_ptr() } else { buffer.as_ptr() };
+        unsafe {
+            let str = self::ffi::magic_buffer(self.cookie.as_ptr(), pbuffer, buffer_len);
+            if str.is_null() {
+                Err(self.magic_failure())
+            } else {
+                Ok(CStr::from_ptr(str).to_bytes().to_vec())
+            }
+        }
+    }
+
+    /// Returns `libmagic`'s message for whatever operation failed last on this cookie
+    ///
+    /// Failing methods already return a `MagicError` with the message and
+    /// `errno` taken during the call itself, so this is rarely needed. It
+    /// may belong to a later call than the one you are handling.
+    pub fn error(&self) -> Option<String> {
+        self.pending_error().map(|e| e.desc)
+    }
+
+    /// Returns the OS error number `libmagic` reported for the last operation, if any
+    pub fn errno(&self) -> Option<i32> {
+        match unsafe { self::ffi::magic_errno(self.cookie.as_ptr()) } {
+            0 => None,
+            errno => Some(errno),
+        }
+    }
+
+    /// Returns `errno` as an `io::ErrorKind`, e.g. to tell `PermissionDenied` from `NotFound`
+    pub fn error_kind(&self) -> Option<io::ErrorKind> {
+        self.errno().map(|errno| io::Error::from_raw_os_error(errno).kind())
+    }
+
+    /// Sets how `file` and `buffer` convert descriptions which are not valid UTF-8
+    pub fn set_decoding(&self, decoding: Decoding) {
+        self.decoding.set(decoding);
+    }
+
+    /// Sets how `file` retries after transient I/O errors, see `Retry`
+    pub fn set_retry(&self, retry: Retry) {
+        self.retry.set(retry);
+    }
+
+    /// Sets the flags to use for subsequent operations
+    ///
+    /// Fails if `libmagic` is too old to support one of the flags, if it
+    /// rejects them, e.g. `PRESERVE_ATIME` on platforms without `utime`, or
+    /// if they ask for conflicting output like `MIME_TYPE` and `APPLE`.
+    /// The previous flags stay in effect then.
+    ///
+    /// Returns the cookie on success, so it can be followed up directly, e.g.
+    /// `cookie.set_flags(flags::MIME_TYPE)?.load_default()?`.
+    pub fn set_flags(&self, flags: self::flags::CookieFlags) -> Result<&Cookie, MagicError> {
+        if let Err(e) = check_flags_supported(flags) {
+            return Err(self.record_failure(e));
+        }
+        let ret = unsafe {
+            self::ffi::magic_setflags(self.cookie.as_ptr(), flags.bits())
+        };
+        if -1 == ret {
+            return Err(self.record_failure(MagicError{desc: format!("flags {:?} are not supported on this platform", flags), errno: None, kind: MagicErrorKind::Other,}));
+        }
+        self.flags.set(flags);
+        Ok(self)
+    }
+
+    /// Returns the flags currently in effect
+    ///
+    /// Asks `libmagic` via `magic_getflags` where available. Older versions
+    /// lack that function, in which case the flags last set through this
+    /// wrapper are returned. `flags::CHECK` is only included if it was set
+    /// through this wrapper, since `libmagic` turns it on itself when loading.
+    pub fn flags(&self) -> self::flags::CookieFlags {
+        match magic_getflags_fn() {
+            Some(getflags) => {
+                let reported = self::flags::CookieFlags::from_bits_truncate(unsafe { getflags(self.cookie.as_ptr()) });
+                reported - (self::flags::CHECK - self.flags.get())
+            },
+            None => self.flags.get(),
+        }
+    }
+
+    /// Sets the limit `param` to `value`, e.g. to bound the resources used per cookie
+    pub fn set_param(&self, param: Param, value: usize) -> Result<&Cookie, MagicError> {
+        let value = value as size_t;
+        let ret = unsafe {
+            self::ffi::magic_setparam(self.cookie.as_ptr(), param.to_c_int(), &value as *const size_t as *const libc::c_void)
+        };
+        if 0 == ret {
+            Ok(self)
+        } else {
+            let e = io::Error::last_os_error();
+            Err(self.record_failure(MagicError{desc: format!("cannot set {:?} ({})", param, e), errno: e.raw_os_error(), kind: MagicErrorKind::Other,}))
+        }
+    }
+
+    /// Returns the current value of the limit `param`
+    pub fn get_param(&self, param: Param) -> Result<usize, MagicError> {
+        self.raw_param(param)
+            .map_err(|e| self.record_failure(MagicError{desc: format!("cannot get {:?} ({})", param, e), errno: e.raw_os_error(), kind: MagicErrorKind::Other,}))
+    }
+
+    fn raw_param(&self, param: Param) -> io::Result<usize> {
+        let mut value: size_t = 0;
+        let ret = unsafe {
+            self::ffi::magic_getparam(self.cookie.as_ptr(), param.to_c_int(), &mut value as *mut size_t as *mut libc::c_void)
+        };
+        if 0 == ret { Ok(value as usize) } else { Err(io::Error::last_os_error()) }
+    }
+
+    /// Limits how many bytes of each file or buffer `libmagic` examines
+    ///
+    /// Only a prefix of at most `max` bytes is read, no matter how large the
+    /// file is. The default depends on the `libmagic` version, e.g. 7 MiB in
+    /// 5.44; zero is rejected since nothing could be detected then.
+    pub fn set_max_bytes(&self, max: usize) -> Result<&Cookie, MagicError> {
+        if 0 == max {
+            return Err(self.record_failure(MagicError{desc: "the maximum number of bytes must not be zero".to_string(), errno: None, kind: MagicErrorKind::Other,}));
+        }
+        self.set_param(Param::BytesMax, max)
+    }
+
+    /// Returns how many bytes of each file or buffer `libmagic` examines at most
+    pub fn max_bytes(&self) -> Result<usize, MagicError> {
+        self.get_param(Param::BytesMax)
+    }
+
+    /// Passes the warnings `libmagic` prints while parsing databases to `handler`, one line at a time
+    ///
+    /// This covers everything `load`, `check` and `compile` print to stderr,
+    /// e.g. warnings about malformed rules and, for `check`, the dump of the
+    /// parsed rules. With `flags::DEBUG` set, the trace `libmagic` prints
+    /// while examining files and buffers is passed on as well, e.g. to be
+    /// forwarded to a logger. The output is captured by temporarily
+    /// redirecting the process' stderr, so anything other threads print to
+    /// stderr meanwhile goes to `handler` as well.
+    pub fn set_warning_handler<F: Fn(&str) + Send + 'static>(&self, handler: F) {
+        *self.warning_handler.borrow_mut() = Some(Box::new(handler));
+    }
+
+    fn captures_debug_output(&self) -> bool {
+        self.flags.get().contains(self::flags::DEBUG) && self.warning_handler.borrow().is_some() && !self.capturing_warnings.get()
+    }
+
+    // Runs `f`, capturing stderr for the warning handler if there is one
+    fn with_warnings<T, F: FnOnce() -> Result<T, MagicError>>(&self, f: F) -> Result<T, MagicError> {
+        if self.warning_handler.borrow().is_none() || self.capturing_warnings.get() {
+            return f();
+        }
+        self.capture_stderr(f).and_then(|(result, _)| result)
+    }
+
+    // Runs `f` with stderr captured, passes the output to the warning handler and returns it
+    fn capture_stderr<T, F: FnOnce() -> T>(&self, f: F) -> Result<(T, String), MagicError> {
+        self.capturing_warnings.set(true);
+        let captured = capture_warnings(f);
+        self.capturing_warnings.set(false);
+
+        let (result, output) = captured?;
+        self.pass_warnings(&output);
+        Ok((result, output))
+    }
+
+    fn pass_warnings(&self, output: &str) {
+        if let Some(ref handler) = *self.warning_handler.borrow() {
+            for line in output.lines().filter(|line| !line.is_empty()) {
+                handler(line);
+            }
+        }
+    }
+
+    // TODO: check, compile, list and load mostly do the same, refactor!
+
+    pub fn check<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<(), MagicError> {
+        let cookie = self.cookie.as_ptr();
+        let db_filenames = db_filenames(filenames)?;
+        // Like `compile` and `list`, this drops the loaded databases
+        *self.databases.borrow_mut() = Databases::None;
+        let ret = self.with_warnings(|| Ok(unsafe { self::ffi::magic_check(cookie, db_filenames_ptr(&db_filenames)) }))?;
+        if 0 == ret { Ok(()) } else { Err(self.magic_failure()) }
+    }
+
+    /// Compiles the databases in `filenames`, returning the paths of the compiled files
+    ///
+    /// `libmagic` writes each compiled database into the current working
+    /// directory, named after the source file plus `.mgc`, and overwrites
+    /// any file of that name there; use `compile_to` to put it elsewhere.
+    /// On failure `last_error` also has the `errno`.
+    pub fn compile<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<Vec<PathBuf>, MagicError> {
+        let cookie = self.cookie.as_ptr();
+        let db_filenames = db_filenames(filenames)?;
+        *self.databases.borrow_mut() = Databases::None;
+        let ret = self.with_warnings(|| Ok(unsafe { self::ffi::magic_compile(cookie, db_filenames_ptr(&db_filenames)) }))?;
+        if 0 != ret {
+            return Err(self.magic_failure());
+        }
+        filenames.iter().map(|filename| {
+            compiled_name(filename.as_ref())
+                .ok_or_else(|| MagicError{desc: format!("`{}' has no file name", filename.as_ref().display()), errno: None, kind: MagicErrorKind::Other,})
+        }).collect()
+    }
+
+    /// Like `compile`, but on failure also returns the problems `libmagic` found in each rule
+    ///
+    /// `libmagic` prints these to stderr, so it is captured meanwhile, like
+    /// for `set_warning_handler`.
+    pub fn compile_detailed<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<Vec<PathBuf>, CompileFailure> {
+        if self.capturing_warnings.get() {
+            return self.compile(filenames).map_err(|error| CompileFailure{error, errors: Vec::new(),});
+        }
+        match self.capture_stderr(|| self.compile(filenames)) {
+            Ok((Ok(compiled), _)) => Ok(compiled),
+            Ok((Err(error), output)) => Err(CompileFailure{error, errors: parse_compile_errors(&output),}),
+            Err(error) => Err(CompileFailure{error, errors: Vec::new(),}),
+        }
+    }
+
+    /// Compiles the database `filename` and moves the result to `output`
+    ///
+    /// `libmagic` compiles into the current working directory first, see
+    /// `compile`. If a file of that name already exists there, and it is
+    /// not `output` itself, this fails instead of overwriting it.
+    pub fn compile_to<P: AsRef<Path>, Q: AsRef<Path>>(&self, filename: P, output: Q) -> Result<(), MagicError> {
+        let filename = filename.as_ref();
+        let output = output.as_ref();
+        if let Some(compiled) = compiled_name(filename) {
+            if compiled != output && std::fs::symlink_metadata(&compiled).is_ok() {
+                let desc = format!("cannot compile `{}' to `{}' without overwriting `{}'", filename.display(), output.display(), compiled.display());
+                return Err(self.record_failure(MagicError{desc, errno: Some(libc::EEXIST), kind: MagicErrorKind::Other,}));
+            }
+        }
+        let compiled = self.compile(&[filename])?.remove(0);
+        if compiled == output {
+            return Ok(());
+        }
+        std::fs::rename(&compiled, output)
+            .or_else(|_| std::fs::copy(&compiled, output).and_then(|_| std::fs::remove_file(&compiled)))
+            .map_err(|e| self.record_failure(MagicError{desc: format!("cannot move `{}' to `{}' ({})", compiled.display(), output.display(), e), errno: None, kind: MagicErrorKind::Other,}))
+    }
+
+    pub fn list<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<(), MagicError> {
+        let cookie = self.cookie.as_ptr();
+        let db_filenames = db_filenames(filenames)?;
+        let ret;
+
+        *self.databases.borrow_mut() = Databases::None;
+        unsafe {
+            ret = self::ffi::magic_list(cookie, db_filenames_ptr(&db_filenames));
+        }
+        if 0 == ret { Ok(()) } else { Err(self.magic_failure()) }
+    }
+
+    /// Like `list`, but returns the entries instead of printing them to stdout
+    ///
+    /// The listing is captured by temporarily redirecting the process' stdout,
+    /// so anything other threads print to stdout meanwhile is lost.
+    pub fn entries<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<Vec<MagicEntry>, MagicError> {
+        let (ret, output) = capture_fd(libc::STDOUT_FILENO, || self.list(filenames))
+            .map_err(|e| MagicError{desc: format!("cannot capture the listing ({})", e), errno: None, kind: MagicErrorKind::Other,})?;
+        ret?;
+        Ok(parse_listing(&String::from_utf8_lossy(&output)))
+    }
+
+    /// Loads the default database `libmagic` was built with
+    ///
+    /// This is the same as `load` with no filenames; `libmagic` honours the
+    /// `MAGIC` environment variable and otherwise uses its compiled-in path.
+    pub fn load_default(&self) -> Result<&Cookie, MagicError> {
+        self.load::<&Path>(&[])
+    }
+
+    /// Loads all databases in `filenames` at once, e.g. a custom one on top of the system one
+    ///
+    /// The same as `load_all`, which takes any kind of paths.
+    pub fn load_many<I: IntoIterator<Item = PathBuf>>(&self, filenames: I) -> Result<&Cookie, MagicError> {
+        self.load_all(filenames)
+    }
+
+    /// Loads all databases in `filenames`, making sure first that each of them exists
+    ///
+    /// A missing database, or a path containing the `:` that `libmagic`
+    /// uses to separate them, fails the call before anything is loaded,
+    /// so the databases loaded before stay in effect. If `libmagic` rejects
+    /// the databases, each is tried on its own to name the first one at fault.
+    pub fn load_all<I, P>(&self, filenames: I) -> Result<&Cookie, MagicError>
+        where I: IntoIterator<Item = P>, P: AsRef<Path> {
+        let filenames: Vec<PathBuf> = filenames.into_iter().map(|filename| filename.as_ref().to_path_buf()).collect();
+        db_filenames(&filenames)?;
+        for filename in &filenames {
+            if let Err(io) = std::fs::metadata(filename) {
+                let desc = format!("cannot load `{}' ({})", filename.display(), io);
+                return Err(self.record_failure(MagicError{desc, errno: io.raw_os_error(), kind: MagicErrorKind::Other,}));
+            }
+        }
+
+        let e = match self.load(&filenames) {
+            Ok(_) => return Ok(self),
+            Err(e) => e,
+        };
+        let rejects = |filename: &&PathBuf| Cookie::open(self.flags.get()).is_ok_and(|probe| probe.load(&[filename]).is_err());
+        match filenames.iter().find(rejects) {
+            Some(filename) => {
+                let desc = format!("{} (while loading `{}')", e.desc, filename.display());
+                Err(self.record_failure(MagicError{desc, errno: e.errno, kind: e.kind,}))
+            },
+            None => Err(e),
+        }
+    }
+
+    /// Loads the databases in `filenames`, or the default one if there are none
+    ///
+    /// On failure the error carries `libmagic`'s diagnostic, e.g. about a
+    /// compiled database of the wrong format version, plus the first path
+    /// that cannot be accessed at all, if there is one.
+    pub fn load<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<&Cookie, MagicError> {
+        // A failed load leaves no databases at all behind
+        *self.databases.borrow_mut() = Databases::None;
+        self.with_warnings(|| self.load_databases(filenames))?;
+        *self.databases.borrow_mut() = if filenames.is_empty() {
+            Databases::Default
+        } else {
+            Databases::Files(filenames.iter().map(|filename| filename.as_ref().to_path_buf()).collect())
+        };
+        Ok(self)
+    }
+
+    fn load_databases<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<(), MagicError> {
+        let cookie = self.cookie.as_ptr();
+        let db_filenames = db_filenames(filenames)?;
+        let ret;
+
+        unsafe {
+            ret = self::ffi::magic_load(cookie, db_filenames_ptr(&db_filenames));
+        }
+        if 0 == ret {
+            return Ok(());
+        }
+        let e = self.magic_failure();
+        if self.recompile_stale.get() && e.desc.contains("supports only version") {
+            if let Some(compiled) = filenames.first() {
+                if self.recompile(compiled.as_ref()).is_ok() {
+                    let ret = unsafe { self::ffi::magic_load(cookie, db_filenames_ptr(&db_filenames)) };
+                    return if 0 == ret { Ok(()) } else { Err(self.magic_failure()) };
+                }
+                // The failed recompile recorded its own error, but the load error is returned
+                self.record_failure(e.clone());
+            }
+        }
+        for filename in filenames {
+            let filename = filename.as_ref();
+            if let Err(io) = std::fs::metadata(filename) {
+                let desc = format!("{} (cannot access `{}': {})", e.desc, filename.display(), io);
+                return Err(self.record_failure(MagicError{desc, errno: io.raw_os_error().or(e.errno), kind: e.kind,}));
+            }
+        }
+        Err(e)
+    }
+
+    /// Replaces the compiled database at `compiled` by compiling the source file next to it
+    fn recompile(&self, compiled: &Path) -> Result<(), MagicError> {
+        let unavailable = || MagicError{desc: format!("no magic source for `{}'", compiled.display()), errno: None, kind: MagicErrorKind::Other,};
+        if compiled.extension().is_none_or(|extension| extension != "mgc") {
+            return Err(unavailable());
+        }
+        let source = compiled.with_extension("");
+        if !source.is_file() {
+            return Err(unavailable());
+        }
+
+        self.compile_to(&source, compiled)
+    }
+
+    /// Sets whether `load` recompiles a `.mgc` database from the source file next to it
+    ///
+    /// A compiled database only works with the `libmagic` version that
+    /// created it, so this helps after upgrading `libmagic`. When enabled,
+    /// `load` reacts to a format version mismatch of the first database by
+    /// compiling the file with the same name minus `.mgc`, replacing the
+    /// stale database and loading again.
+    pub fn set_recompile_stale(&self, recompile: bool) {
+        self.recompile_stale.set(recompile);
+    }
+
+    /// Loads compiled magic databases from memory instead of from files
+    ///
+    /// The `buffers` are copied and kept alive together with the cookie,
+    /// since `libmagic` uses them in place rather than parsing them once.
+    pub fn load_buffers<B: AsRef<[u8]>>(&self, buffers: &[B]) -> Result<&Cookie, MagicError> {
+        let mut owned: Vec<Vec<u8>> = buffers.iter().map(|buffer| buffer.as_ref().to_vec()).collect();
+        let mut pointers: Vec<*mut libc::c_void> = owned.iter_mut().map(|buffer| buffer.as_mut_ptr() as *mut libc::c_void).collect();
+        let mut sizes: Vec<size_t> = owned.iter().map(|buffer| buffer.len() as size_t).collect();
+
+        let ret = unsafe {
+            self::ffi::magic_load_buffers(self.cookie.as_ptr(), pointers.as_mut_ptr(), sizes.as_mut_ptr(), owned.len() as size_t)
+        };
+        if 0 == ret {
+            // Moving the outer `Vec` leaves the buffers themselves where they are
+            *self.buffers.borrow_mut() = owned;
+            *self.databases.borrow_mut() = Databases::Buffers(buffers.len());
+            Ok(self)
+        } else {
+            *self.databases.borrow_mut() = Databases::None;
+            Err(self.magic_failure())
+        }
+    }
+
+    /// Replaces the loaded databases by the ones in `filenames`, but only if they all load
+    ///
+    /// The databases are loaded into a fresh cookie with the same flags and
+    /// parameters first, so on failure the old ones are still in effect.
+    pub fn reload<P: AsRef<Path>>(&mut self, filenames: &[P]) -> Result<(), MagicError> {
+        let fresh = self.open_alike()?;
+        let loaded = self.with_warnings(|| fresh.load(filenames).map(|_| ()));
+        self.adopt(fresh, loaded)
+    }
+
+    // Takes over the databases `fresh` loaded, or records why it failed to load them
+    fn adopt(&mut self, mut fresh: Cookie, loaded: Result<(), MagicError>) -> Result<(), MagicError> {
+        if let Err(e) = loaded {
+            return Err(self.record_failure(e));
+        }
+        std::mem::swap(&mut self.cookie, &mut fresh.cookie);
+        std::mem::swap(&mut self.buffers, &mut fresh.buffers);
+        std::mem::swap(&mut self.databases, &mut fresh.databases);
+        Ok(())
+    }
+
+    /// Opens another cookie with the same flags, parameters and settings, and loads the same databases
+    ///
+    /// The clone is independent, e.g. for another thread. A warning handler
+    /// is not carried over, since it cannot be cloned.
+    pub fn try_clone(&self) -> Result<Cookie, MagicError> {
+        let clone = self.open_alike()?;
+        clone.set_decoding(self.decoding.get());
+        clone.set_retry(self.retry.get());
+
+        match *self.databases.borrow() {
+            Databases::None => {},
+            Databases::Default => { clone.load_default()?; },
+            Databases::Files(ref filenames) => { clone.load(filenames)?; },
+            Databases::Buffers(_) => { clone.load_buffers(&self.buffers.borrow())?; },
+            Databases::Overlay(ref filenames, ref overlay) => { clone.load_with_overlay(filenames, overlay)?; },
+        }
+        Ok(clone)
+    }
+
+    // Opens a cookie with the same flags and settings, but without databases
+    fn open_alike(&self) -> Result<Cookie, MagicError> {
+        let fresh = Cookie::open(self.flags.get())?;
+        self.copy_settings(&fresh)?;
+        Ok(fresh)
+    }
+
+    // Gives `other` the same flags, parameters and recompile setting
+    fn copy_settings(&self, other: &Cookie) -> Result<(), MagicError> {
+        // `open` always adds `flags::ERROR`
+        other.set_flags(self.flags.get())?;
+        for &param in &Param::all() {
+            // Older `libmagic` versions lack some of the parameters
+            if let Ok(value) = self.raw_param(param) {
+                other.set_param(param, value)?;
+            }
+        }
+        other.set_recompile_stale(self.recompile_stale.get());
+        Ok(())
+    }
+
+    /// Loads magic source files or directories of them, leaving out what `overlay` disables
+    ///
+    /// The remaining rules are combined into one temporary source file,
+    /// which only the current user can access, is loaded and then removed
+    /// again. Sources that are not valid UTF-8 are passed on byte for byte.
+    pub fn load_with_overlay<P: AsRef<Path>>(&self, filenames: &[P], overlay: &rules::Overlay) -> Result<&Cookie, MagicError> {
+        let io_failure = |path: &Path, e: io::Error| MagicError{desc: format!("cannot read `{}' ({})", path.display(), e), errno: None, kind: MagicErrorKind::Other,};
+
+        let mut sources = Vec::new();
+        for filename in filenames {
+            let filename = filename.as_ref();
+            if filename.is_dir() {
+                let mut entries = std::fs::read_dir(filename)
+                    .and_then(|dir| dir.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<Vec<_>>>())
+                    .map_err(|e| io_failure(filename, e))?;
+                entries.sort();
+                sources.extend(entries);
+            } else {
+                sources.push(filename.to_path_buf());
+            }
+        }
+
+        let mut combined = Vec::new();
+        for source in sources.iter().filter(|source| overlay.allows_file(source)) {
+            let bytes = std::fs::read(source).map_err(|e| io_failure(source, e))?;
+            // Sources that are not UTF-8 are taken as Latin-1, which maps every byte to one `char` and back
+            let (text, latin1) = match String::from_utf8(bytes) {
+                Ok(text) => (text, false),
+                Err(e) => (e.into_bytes().iter().map(|&byte| byte as char).collect(), true),
+            };
+            let db = rules::Database::parse(&text).map_err(|e| MagicError{desc: format!("{}: {}", source.display(), e), errno: None, kind: MagicErrorKind::Other,})?;
+            let filtered = rules::to_source(&overlay.apply(&db).items);
+            if latin1 {
+                combined.extend(filtered.chars().map(|c| c as u8));
+            } else {
+                combined.extend_from_slice(filtered.as_bytes());
+            }
+        }
+
+        let (path, mut file) = create_private_file("rust-magic-overlay").map_err(|e| io_failure(&std::env::temp_dir(), e))?;
+        let written = file.write_all(&combined);
+        drop(file);
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&path);
+            return Err(io_failure(&path, e));
+        }
+        let ret = self.load(&[&path]);
+        let _ = std::fs::remove_file(&path);
+        ret?;
+        *self.databases.borrow_mut() = Databases::Overlay(filenames.iter().map(|filename| filename.as_ref().to_path_buf()).collect(), overlay.clone());
+        Ok(self)
+    }
+
+    pub fn open(flags: self::flags::CookieFlags) -> Result<Cookie, MagicError> {
+        let flags = flags | self::flags::ERROR;
+        check_flags_supported(flags)?;
+        match RawCookie::open(flags.bits()) {
+            Some(cookie) => Ok(Cookie::with_raw_cookie(cookie, flags)),
+            None => {
+                let e = io::Error::last_os_error();
+                Err(self::MagicError{desc: format!("cannot open cookie ({})", e), errno: e.raw_os_error(), kind: MagicErrorKind::Other,})
+            },
+        }
+    }
+
+    /// Opens a cookie with `flags::NONE` and loads the default database
+    pub fn try_default() -> Result<Cookie, MagicError> {
+        let cookie = Cookie::open(self::flags::NONE)?;
+        cookie.load_default()?;
+        Ok(cookie)
+    }
+
+    /// Returns a `CookieBuilder` for opening, configuring and loading a cookie in one expression
+    pub fn builder() -> CookieBuilder {
+        CookieBuilder{flags: self::flags::NONE, databases: Vec::new(), params: Vec::new(),}
+    }
+
+    /// Takes ownership of a `magic_t` opened elsewhere, e.g. by C code
+    ///
+    /// The cookie is closed when the returned `Cookie` is dropped. Its flags
+    /// are queried via `magic_getflags` where available, except for
+    /// `flags::CHECK`, which `libmagic` also sets itself when loading.
+    ///
+    /// # Safety
+    ///
+    /// `cookie` must be a valid, non-null `magic_t` that nobody else closes
+    /// or uses while the `Cookie` is alive.
+    pub unsafe fn from_raw(cookie: *const self::ffi::Magic) -> Cookie {
+        let flags = match magic_getflags_fn() {
+            Some(getflags) => self::flags::CookieFlags::from_bits_truncate(getflags(cookie)) - self::flags::CHECK,
+            None => self::flags::NONE,
+        };
+        Cookie::with_raw_cookie(RawCookie(cookie), flags)
+    }
+
+    fn with_raw_cookie(cookie: RawCookie, flags: self::flags::CookieFlags) -> Cookie {
+        Cookie {cookie, decoding: Cell::new(Decoding::Strict), retry: Cell::new(Retry::none()), last_error: RefCell::new(None), recompile_stale: Cell::new(false), flags: Cell::new(flags), buffers: RefCell::new(Vec::new()), databases: RefCell::new(Databases::None), warning_handler: RefCell::new(None), capturing_warnings: Cell::new(false),}
+    }
+
+    /// Releases the `magic_t` without closing it, e.g. to hand it to C code
+    ///
+    /// The caller becomes responsible for calling `magic_close`. Databases
+    /// loaded with `load_buffers` are leaked, since `libmagic` keeps using them.
+    pub fn into_raw(self) -> *const self::ffi::Magic {
+        let Cookie {cookie, buffers, ..} = self;
+        std::mem::forget(buffers);
+        cookie.into_raw()
+    }
+
+    /// Closes the cookie right away instead of when it is dropped
+    ///
+    /// Returns the error of the last failed operation, if `libmagic` still
+    /// has one pending; the cookie is closed either way.
+    pub fn close(self) -> Result<(), MagicError> {
+        let pending = self.pending_error();
+        drop(self);
+        match pending {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for Cookie {
+    /// Like `Cookie::try_default`, but panics if the cookie cannot be set up
+    fn default() -> Cookie {
+        Cookie::try_default().unwrap_or_else(|e| panic!("cannot set up the default cookie: {}", e))
+    }
+}
+
+
+/// Flags set on a `Cookie` for as long as this guard lives, see `Cookie::scoped_flags`
+///
+/// The previous flags are restored on drop, even when unwinding, so a
+/// helper cannot leak its flags into other code sharing the cookie. The
+/// cookie is available through `Deref` meanwhile.
+pub struct ScopedFlags<'a> {
+    cookie: &'a Cookie,
+    prior: self::flags::CookieFlags,
+}
+
+impl<'a> std::ops::Deref for ScopedFlags<'a> {
+    type Target = Cookie;
+
+    fn deref(&self) -> &Cookie {
+        self.cookie
+    }
+}
+
+impl<'a> Drop for ScopedFlags<'a> {
+    fn drop(&mut self) {
+        // These flags were in effect before, so `libmagic` accepts them again
+        let _ = self.cookie.set_flags(self.prior);
+    }
+}
+
+
+/// Opens and configures a `Cookie` in one go, see `Cookie::builder`
+#[derive(Clone, Debug)]
+pub struct CookieBuilder {
+    flags: self::flags::CookieFlags,
+    databases: Vec<PathBuf>,
+    params: Vec<(Param, usize)>,
+}
+
+impl CookieBuilder {
+    pub fn flags(mut self, flags: self::flags::CookieFlags) -> CookieBuilder {
+        self.flags = flags;
+        self
+    }
+
+    /// Adds a database to load; without any, the default database is loaded
+    pub fn database<P: AsRef<Path>>(mut self, database: P) -> CookieBuilder {
+        self.databases.push(database.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn param(mut self, param: Param, value: usize) -> CookieBuilder {
+        self.params.push((param, value));
+        self
+    }
+
+    /// Opens the cookie, sets the parameters and loads the databases
+    pub fn build(self) -> Result<Cookie, MagicError> {
+        let cookie = Cookie::open(self.flags)?;
+        for (param, value) in self.params {
+            cookie.set_param(param, value)?;
+        }
+        cookie.load(&self.databases)?;
+        Ok(cookie)
+    }
+}
+
+
+/// A `Cookie` that can be shared between threads
+///
+/// A `Cookie` is `Send` but not `Sync`: it may move to another thread, but
+/// `libmagic` cannot run several operations on one cookie at the same time.
+/// This wraps it in a `Mutex`, so each call waits for the others to finish.
+/// Use `lock` for whatever the convenience methods do not cover.
+#[derive(Debug)]
+pub struct SharedCookie {
+    cookie: std::sync::Mutex<Cookie>,
+}
+
+impl SharedCookie {
+    pub fn new(cookie: Cookie) -> SharedCookie {
+        SharedCookie{cookie: std::sync::Mutex::new(cookie),}
+    }
+
+    /// Waits for exclusive access to the cookie
+    ///
+    /// A panic while the cookie was locked leaves it usable, so the lock is
+    /// taken even if it is poisoned.
+    pub fn lock(&self) -> std::sync::MutexGuard<'_, Cookie> {
+        self.cookie.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn into_inner(self) -> Cookie {
+        self.cookie.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn file<P: AsRef<Path>>(&self, filename: P) -> Result<String, MagicError> {
+        self.lock().file(filename)
+    }
+
+    pub fn buffer<B: AsRef<[u8]>>(&self, buffer: B) -> Result<String, MagicError> {
+        self.lock().buffer(buffer)
+    }
+
+    pub fn set_flags(&self, flags: self::flags::CookieFlags) -> Result<(), MagicError> {
+        self.lock().set_flags(flags).map(|_| ())
+    }
+
+    pub fn load<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<(), MagicError> {
+        self.lock().load(filenames).map(|_| ())
+    }
+
+    pub fn load_default(&self) -> Result<(), MagicError> {
+        self.lock().load_default().map(|_| ())
+    }
+
+    /// Like `Cookie::reload`, but other threads keep using the old databases meanwhile
+    ///
+    /// The new databases are loaded without holding the lock, which is only
+    /// taken to set up the new cookie and to swap it in. Flags and
+    /// parameters changed in between carry over.
+    pub fn reload<P: AsRef<Path>>(&self, filenames: &[P]) -> Result<(), MagicError> {
+        let cookie = self.lock();
+        let fresh = cookie.open_alike()?;
+        let warnings = cookie.warning_handler.borrow().is_some();
+        drop(cookie);
+
+        let (loaded, output) = if warnings {
+            capture_warnings(|| fresh.load(filenames).map(|_| ()))?
+        } else {
+            (fresh.load(filenames).map(|_| ()), String::new())
+        };
+
+        let mut cookie = self.lock();
+        cookie.pass_warnings(&output);
+        if loaded.is_ok() {
+            cookie.copy_settings(&fresh)?;
+        }
+        cookie.adopt(fresh, loaded)
+    }
+}
+
+impl From<Cookie> for SharedCookie {
+    fn from(cookie: Cookie) -> SharedCookie {
+        SharedCookie::new(cookie)
+    }
+}
+
+
+/// Describes the contents of `filename` using the default database, without managing a `Cookie`
+///
+/// Each call opens and loads a new cookie, so keep a `Cookie` around for
+/// more than a few files.
+pub fn from_file<P: AsRef<Path>>(filename: P) -> Result<String, MagicError> {
+    Cookie::try_default()?.file(filename)
+}
+
+/// Like `from_file`, but describes the contents of `buffer`
+pub fn from_buffer<B: AsRef<[u8]>>(buffer: B) -> Result<String, MagicError> {
+    Cookie::try_default()?.buffer(buffer)
+}
+
+
+thread_local! {
+    static GLOBAL: RefCell<Option<std::rc::Rc<Cookie>>> = const { RefCell::new(None) };
+    static GLOBAL_FLAGS: Cell<self::flags::CookieFlags> = const { Cell::new(self::flags::NONE) };
+}
+
+/// Returns this thread's shared cookie, opening it with the default database on first use
+///
+/// # Panics
+///
+/// If the cookie cannot be opened or the default database cannot be
+/// loaded; use `try_global` to handle that instead.
+pub fn global() -> std::rc::Rc<Cookie> {
+    try_global().unwrap_or_else(|e| panic!("cannot set up the global cookie: {}", e))
+}
+
+/// Like `global`, but returns an error instead of panicking
+pub fn try_global() -> Result<std::rc::Rc<Cookie>, MagicError> {
+    if let Some(cookie) = GLOBAL.with(|global| global.borrow().clone()) {
+        return Ok(cookie);
+    }
+    let cookie = Cookie::open(GLOBAL_FLAGS.with(|flags| flags.get()))?;
+    cookie.load_default()?;
+    let cookie = std::rc::Rc::new(cookie);
+    GLOBAL.with(|global| *global.borrow_mut() = Some(cookie.clone()));
+    Ok(cookie)
+}
+
+/// Sets the flags of this thread's `global` cookie, now or whenever it is opened
+///
+/// The flags are checked like `Cookie::set_flags` does even before the
+/// cookie is opened, so that `global` does not panic on them later.
+pub fn set_global_flags(flags: self::flags::CookieFlags) -> Result<(), MagicError> {
+    match GLOBAL.with(|global| global.borrow().clone()) {
+        Some(cookie) => { cookie.set_flags(flags)?; },
+        None => check_flags_supported(flags)?,
+    }
+    GLOBAL_FLAGS.with(|global| global.set(flags));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate regex;
+
+    use super::Cookie;
+	use super::flags;
+
+    // Using relative paths to test files should be fine, since cargo doc
+    // http://doc.crates.io/build-script.html#inputs-to-the-build-script
+    // states that cwd == CARGO_MANIFEST_DIR
+
+    // A path in the temporary directory that tests running at the same time do not use
+    fn temp_path<S: AsRef<std::ffi::OsStr>>(name: S) -> std::path::PathBuf {
+        let mut file_name = std::ffi::OsString::from(format!("rust-magic-{}-", std::process::id()));
+        file_name.push(name);
+        std::env::temp_dir().join(file_name)
+    }
+
+    // `libmagic` compiles into the working directory, and changing it would
+    // affect the other tests. So a test that compiles makes this run it again
+    // in a child process with a new temporary working directory, and only
+    // goes on in the child, where this returns `true`.
+    fn in_temp_cwd(test: &str) -> bool {
+        if std::env::var_os("RUST_MAGIC_TEST_CWD").is_some() {
+            return true;
+        }
+        let dir = temp_path(test);
+        std::fs::create_dir(&dir).unwrap();
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", &format!("tests::{}", test), "--test-threads=1"])
+            .env("RUST_MAGIC_TEST_CWD", "1")
+            .current_dir(&dir)
+            .output().unwrap();
+        let left = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success() && stdout.contains("1 passed"), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+        assert_eq!(left, 0);
+        false
+    }
+
+    // The absolute path of a file in `data/tests`, for tests running elsewhere
+    fn test_data(name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("data/tests").join(name)
+    }
+
+    // The header of a compiled database with format version 17, which current `libmagic` rejects
+    fn stale_database() -> Vec<u8> {
+        let mut header = vec![0x1c, 0x04, 0x1e, 0xf1, 17, 0, 0, 0];
+        header.resize(376, 0);
+        header
+    }
+
+    #[test]
+    fn file() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+
+        let path = "data/tests/rust-logo-128x128-blk.png";
+
+        assert_eq!(cookie.file(path).ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+        assert_eq!(cookie.file_lossy(path).ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+
+        assert!(cookie.set_flags(flags::MIME_TYPE).is_ok());
+        assert_eq!(cookie.file(path).ok().unwrap(), "image/png");
+
+        assert!(cookie.set_flags(flags::MIME_TYPE | flags::MIME_ENCODING).is_ok());
+        assert_eq!(cookie.file(path).ok().unwrap(), "image/png; charset=binary");
+    }
+
+    #[test]
+    fn buffer() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-python"]).is_ok());
+
+        let s = b"#!/usr/bin/env python\nprint('Hello, world!')";
+        assert_eq!(cookie.buffer(s).ok().unwrap(), "Python script, ASCII text executable");
+
+        assert!(cookie.set_flags(flags::MIME_TYPE).is_ok());
+        assert_eq!(cookie.buffer(s).ok().unwrap(), "text/x-python");
+    }
+
+    #[test]
+    fn buffer_empty() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-python"]).is_ok());
+
+        assert_eq!(cookie.buffer([]).ok().unwrap(), "empty");
+        assert_eq!(cookie.buffer_match([]).ok().unwrap(), Some("empty".to_string()));
+
+        assert!(cookie.set_flags(flags::MIME_TYPE).is_ok());
+        assert_eq!(cookie.buffer([]).ok().unwrap(), "application/x-empty");
+    }
+
+    #[test]
+    fn buffer_decoding() {
+        use super::Decoding;
+
+        let cookie = Cookie::open(flags::RAW).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-raw-label"]).is_ok());
+
+        let s = b"RMGCcaf\xe9";
+        assert_eq!(cookie.buffer_bytes(s).ok().unwrap(), b"Rust magic test data, label caf\xe9".to_vec());
+        assert!(cookie.buffer(s).is_err());
+        assert_eq!(cookie.buffer_lossy(s).ok().unwrap(), "Rust magic test data, label caf\u{fffd}");
+
+        cookie.set_decoding(Decoding::Lossy);
+        assert_eq!(cookie.buffer(s).ok().unwrap(), "Rust magic test data, label caf\u{fffd}");
+    }
+
+    #[test]
+    fn buffer_progressive() {
+        use std::io::Read;
+
+        struct Counting<R> { inner: R, read: usize }
+        impl<R: Read> Read for Counting<R> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = self.inner.read(buf)?;
+                self.read += n;
+                Ok(n)
+            }
+        }
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+
+        let png = std::fs::File::open("data/tests/rust-logo-128x128-blk.png").unwrap();
+        let mut input = Counting{inner: png.chain(std::io::repeat(0)), read: 0};
+        assert_eq!(cookie.buffer_progressive(&mut input).ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+        assert_eq!(input.read, 4 << 10);
+
+        let mut input = Counting{inner: std::io::repeat(0).take(2 << 20), read: 0};
+        assert_eq!(cookie.buffer_progressive(&mut input).ok().unwrap(), "data");
+        assert_eq!(input.read, 1 << 20);
+
+        assert!(cookie.set_flags(flags::MIME).is_ok());
+        let mut input = Counting{inner: std::io::repeat(0).take(2 << 20), read: 0};
+        assert_eq!(cookie.buffer_progressive(&mut input).ok().unwrap(), "application/octet-stream; charset=binary");
+        assert_eq!(input.read, 1 << 20);
+    }
+
+    #[test]
+    fn buffer_progressive_with() {
+        use super::Progressive;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-python"]).is_ok());
+
+        let s = b"#!/usr/bin/env python\nprint('Hello, world!')";
+        let policy = Progressive::default().prefixes(vec![2, 8, 64]).specific(|description| description.starts_with("Python"));
+        assert_eq!(cookie.buffer_progressive_with(&s[..], &policy).ok().unwrap(), "Python script, ASCII text executable");
+
+        let policy = Progressive::default().prefixes(vec![2]).specific(|description| description.starts_with("Python"));
+        assert_eq!(cookie.buffer_progressive_with(&s[..], &policy).ok().unwrap(), "ASCII text, with no line terminators");
+
+        // The default stopping rule has to recognize the MIME fallbacks as well
+        assert!(cookie.set_flags(flags::MIME).is_ok());
+        let policy = Progressive::default().prefixes(vec![2, 8, 64]);
+        assert_eq!(cookie.buffer_progressive_with(&s[..], &policy).ok().unwrap(), "text/plain; charset=us-ascii");
+        let mut input = std::io::Cursor::new(vec![0; 256]);
+        assert_eq!(cookie.buffer_progressive_with(&mut input, &policy).ok().unwrap(), "application/octet-stream; charset=binary");
+        assert_eq!(input.position(), 64);
+    }
+
+    #[test]
+    fn descriptor() {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-python"]).is_ok());
+
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        writer.write_all(b"#!/usr/bin/env python\nprint('Hello, world!')").unwrap();
+        drop(writer);
+        assert_eq!(cookie.descriptor(reader.as_raw_fd()).ok().unwrap(), "Python script, ASCII text executable");
+    }
+
+    #[test]
+    fn fileobj() {
+        use std::io::{Seek, SeekFrom};
+
+        let cookie = Cookie::open(flags::MIME_TYPE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+
+        let mut file = std::fs::File::open("data/tests/rust-logo-128x128-blk.png").unwrap();
+        assert_eq!(cookie.fileobj(&file).ok().unwrap(), "image/png");
+
+        file.seek(SeekFrom::Start(1)).unwrap();
+        assert_eq!(cookie.fileobj(&file).ok().unwrap(), "application/octet-stream");
+    }
+
+    #[test]
+    fn detect() {
+        use std::io::Read;
+        use std::path::Path;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+        let path = "data/tests/rust-logo-128x128-blk.png";
+        let expected = "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced";
+
+        assert_eq!(cookie.detect(Path::new(path)).ok().unwrap(), expected);
+        assert_eq!(cookie.detect(&std::fs::File::open(path).unwrap()).ok().unwrap(), expected);
+        assert_eq!(cookie.detect(&std::fs::read(path).unwrap()[..]).ok().unwrap(), expected);
+
+        let mut file = std::fs::File::open(path).unwrap();
+        let reader: &mut dyn Read = &mut file;
+        assert_eq!(cookie.detect(reader).ok().unwrap(), expected);
+    }
+
+    #[test]
+    fn detect_file() {
+        use super::DetectionResult;
+
+        let cookie = Cookie::open(flags::COMPRESS).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-extensions"]).is_ok());
+        let flags = cookie.flags();
+
+        assert_eq!(cookie.detect_file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), DetectionResult{
+            description: "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced".to_string(),
+            mime_type: "image/png".to_string(),
+            encoding: "binary".to_string(),
+            extensions: vec!["png".to_string()],
+        });
+        assert_eq!(cookie.detect_file("data/tests/rust-logo-128x128-blk.png.gz").ok().unwrap(), DetectionResult{
+            description: "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced (gzip compressed data)".to_string(),
+            mime_type: "image/png".to_string(),
+            encoding: "binary".to_string(),
+            extensions: vec!["png".to_string()],
+        });
+        assert_eq!(cookie.detect_file("data/tests").ok().unwrap().mime_type, "inode/directory");
+
+        // Checks that need the open file, like those for ELF executables, apply too
+        let system = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(system.load_default().is_ok());
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(system.detect_file(&exe).ok().unwrap().description, system.file(&exe).ok().unwrap());
+
+        let source = temp_path("detect-atime");
+        std::fs::copy("data/tests/rust-logo-128x128-blk.png", &source).unwrap();
+        let accessed = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        std::fs::File::options().write(true).open(&source).unwrap().set_times(std::fs::FileTimes::new().set_accessed(accessed)).unwrap();
+        assert!(cookie.set_flags(flags | flags::PRESERVE_ATIME).is_ok());
+        assert_eq!(cookie.detect_file(&source).ok().unwrap().mime_type, "image/png");
+        assert_eq!(std::fs::metadata(&source).unwrap().accessed().unwrap(), accessed);
+        std::fs::remove_file(&source).unwrap();
+        assert!(cookie.set_flags(flags).is_ok());
+
+        let link = temp_path("detect-link");
+        std::os::unix::fs::symlink(std::fs::canonicalize("data/tests/rust-logo-128x128-blk.png").unwrap(), &link).unwrap();
+        let unfollowed = cookie.detect_file(&link).ok().unwrap();
+        assert!(cookie.set_flags(flags::COMPRESS | flags::SYMLINK).is_ok());
+        let followed = cookie.detect_file(&link).ok().unwrap();
+        std::fs::remove_file(&link).unwrap();
+        assert!(unfollowed.description.starts_with("symbolic link to"));
+        assert_eq!(unfollowed.mime_type, "inode/symlink");
+        assert_eq!(followed.mime_type, "image/png");
+        assert!(cookie.set_flags(flags).is_ok());
+        assert!(cookie.detect_file("non-existent_file.txt").is_err());
+        assert_eq!(cookie.flags(), flags);
+    }
+
+    #[test]
+    fn file_mime() {
+        let cookie = Cookie::open(flags::APPLE | flags::COMPRESS).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-extensions"]).is_ok());
+        let flags = cookie.flags();
+
+        assert_eq!(cookie.file_mime("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "image/png");
+        assert_eq!(cookie.file_mime("data/tests/rust-logo-128x128-blk.png.gz").ok().unwrap(), "image/png");
+        assert_eq!(cookie.file_encoding("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "binary");
+        assert_eq!(cookie.file_description("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+        assert!(cookie.file_mime("non-existent_file.txt").is_err());
+        assert_eq!(cookie.flags(), flags);
+
+        // Without `flags::ERROR`, failures are reported as results like with `file`
+        assert!(cookie.set_flags(flags::COMPRESS).is_ok());
+        assert!(cookie.file_mime("non-existent_file.txt").ok().unwrap().starts_with("cannot open"));
+        assert_eq!(cookie.flags(), flags::COMPRESS);
+    }
+
+    #[test]
+    fn buffer_mime() {
+        let cookie = Cookie::open(flags::EXTENSION).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-extensions"]).is_ok());
+        let flags = cookie.flags();
+        let png = std::fs::read("data/tests/rust-logo-128x128-blk.png").unwrap();
+
+        assert_eq!(cookie.buffer_mime(&png).ok().unwrap(), "image/png");
+        assert_eq!(cookie.buffer_encoding(b"plain text\n").ok().unwrap(), "us-ascii");
+        assert_eq!(cookie.buffer_description(&png).ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+        assert_eq!(cookie.buffer_extensions(&png).ok().unwrap(), vec!["png"]);
+        assert_eq!(cookie.flags(), flags);
+
+        let compressed = std::fs::read("data/tests/rust-logo-128x128-blk.png.gz").unwrap();
+        assert!(cookie.set_flags(flags::COMPRESS).is_ok());
+        assert_eq!(cookie.buffer_extensions(&compressed).ok().unwrap(), vec!["png"]);
+        assert_eq!(cookie.flags(), flags::COMPRESS);
+    }
+
+    #[test]
+    fn owned_arguments() {
+        use std::path::PathBuf;
+
+        let cookie = Cookie::open(flags::MIME_TYPE).ok().unwrap();
+        assert!(cookie.load(&[String::from("data/tests/db-images-png")]).is_ok());
+        let png = std::fs::read("data/tests/rust-logo-128x128-blk.png").unwrap();
+
+        assert_eq!(cookie.file(PathBuf::from("data/tests/rust-logo-128x128-blk.png")).ok().unwrap(), "image/png");
+        assert_eq!(cookie.buffer(png.clone()).ok().unwrap(), "image/png");
+        assert_eq!(cookie.buffer(&png[..]).ok().unwrap(), "image/png");
+        assert_eq!(cookie.buffer_mime(png).ok().unwrap(), "image/png");
+    }
+
+    #[test]
+    fn file_matches() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-extensions"]).is_ok());
+
+        let matches = cookie.file_matches("data/tests/rust-logo-128x128-blk.png").ok().unwrap();
+        let matches: Vec<String> = matches.iter().map(|m| m.to_string()).collect();
+        assert_eq!(matches, vec!["PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced", "data"]);
+        assert_eq!(cookie.flags(), flags::ERROR);
+
+        // With `flags::RAW` the matches are separated by a real newline rather than `\012`
+        assert!(cookie.set_flags(flags::RAW).is_ok());
+        let png = std::fs::read("data/tests/rust-logo-128x128-blk.png").unwrap();
+        let raw_matches: Vec<String> = cookie.buffer_matches(&png).ok().unwrap().iter().map(|m| m.to_string()).collect();
+        assert_eq!(raw_matches, matches);
+
+        assert!(cookie.set_flags(flags::MIME).is_ok());
+        let matches = cookie.file_matches("data/tests/rust-logo-128x128-blk.png").ok().unwrap();
+        assert_eq!(matches[0].description, "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+        assert_eq!(cookie.flags(), flags::MIME);
+
+        let matches = super::parse_matches("Zip archive data, at least v0.0\\012-  to extract, compression method=store\\012- data");
+        assert_eq!(matches[1].description, "to extract, compression method=store");
+        assert_eq!(matches.len(), 3);
+    }
+
+    #[test]
+    fn file_extensions() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-extensions"]).is_ok());
+        let flags = cookie.flags();
+
+        assert_eq!(cookie.file_extensions("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), vec!["png"]);
+        assert_eq!(cookie.file_extensions("data/tests/rust-logo-128x128-blk.png.gz").ok().unwrap(), vec!["gz", "tgz"]);
+        assert!(cookie.file_extensions("README.md").ok().unwrap().is_empty());
+        assert_eq!(cookie.flags(), flags);
+
+        assert!(cookie.set_flags(flags::COMPRESS).is_ok());
+        assert_eq!(cookie.file_extensions("data/tests/rust-logo-128x128-blk.png.gz").ok().unwrap(), vec!["png"]);
+        assert_eq!(cookie.flags(), flags::COMPRESS);
+
+        assert_eq!(super::parse_extensions("jpeg/jpg/jpe/jfif"), vec!["jpeg", "jpg", "jpe", "jfif"]);
+        assert_eq!(super::parse_extensions("png (???)"), vec!["png"]);
+    }
+
+    #[test]
+    fn apple_type() {
+        use super::AppleType;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-raw-label"]).is_ok());
+
+        assert_eq!(cookie.buffer_apple_type(b"RMGCcafe").ok().unwrap(), Some(AppleType{creator: *b"RUST", kind: *b"RMGC",}));
+        assert_eq!(cookie.buffer_apple_type(b"nothing").ok().unwrap(), None);
+        assert_eq!(cookie.file_apple_type("README.md").ok().unwrap(), None);
+        assert_eq!(cookie.buffer(b"RMGCcafe").ok().unwrap(), "Rust magic test data, label cafe");
+
+        let compressed = [0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x0b, 0xf2, 0x75, 0x77, 0x4e, 0x4e,
+                          0x4c, 0x4b, 0x05, 0x00, 0x2a, 0x87, 0x0a, 0x34, 0x08, 0x00, 0x00, 0x00];
+        assert!(cookie.set_flags(flags::COMPRESS).is_ok());
+        assert_eq!(cookie.buffer_apple_type(compressed).ok().unwrap(), Some(AppleType{creator: *b"RUST", kind: *b"RMGC",}));
+        assert_eq!(cookie.flags(), flags::COMPRESS);
+
+        assert_eq!(AppleType::parse(b"????PNGf"), Some(AppleType{creator: *b"????", kind: *b"PNGf",}));
+        assert_eq!(AppleType::parse(b"PNGf"), None);
+        assert_eq!(AppleType::parse(b"????PNGf (UNKNUNKN)"), Some(AppleType{creator: *b"????", kind: *b"PNGf",}));
+    }
+
+    #[test]
+    fn file_match() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+
+        assert_eq!(cookie.file_match("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), Some("PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced".to_string()));
+        assert_eq!(cookie.buffer_match([0x00, 0xff, 0x10, 0x80]).ok().unwrap(), None);
+        assert!(cookie.file_match("non-existent_file.txt").is_err());
+
+        assert!(cookie.set_flags(flags::MIME).is_ok());
+        assert_eq!(cookie.buffer_match([0x00, 0xff, 0x10, 0x80]).ok().unwrap(), None);
+        assert_eq!(cookie.file_match("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), Some("image/png; charset=binary".to_string()));
+    }
+
+    #[test]
+    fn file_error() {
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load::<&str>(&[]).is_ok());
+
+        let ret = cookie.file("non-existent_file.txt");
+        assert!(ret.is_err());
+        assert_eq!(ret.err().unwrap().desc, "cannot stat `non-existent_file.txt' (No such file or directory)");
+    }
+
+    #[test]
+    fn errno() {
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load_default().is_ok());
+        assert!(cookie.file("data/tests/rust-logo-128x128-blk.png").is_ok());
+        assert_eq!(cookie.errno(), None);
+
+        assert_eq!(cookie.file("non-existent_file.txt").err().unwrap().errno, Some(super::libc::ENOENT));
+        assert_eq!(cookie.errno(), Some(super::libc::ENOENT));
+        assert_eq!(cookie.error_kind(), Some(std::io::ErrorKind::NotFound));
+
+        let e = cookie.file("non-existent_file.txt").err().unwrap();
+        assert_eq!(e.io_error_kind(), Some(std::io::ErrorKind::NotFound));
+        let e: std::io::Error = e.into();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(e.to_string(), "cannot stat `non-existent_file.txt' (No such file or directory)");
+
+        let e: std::io::Error = cookie.file("a\0b").err().unwrap().into();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn error_returned_with_failure() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load_default().is_ok());
+
+        let first = cookie.file("non-existent_file.txt").err().unwrap();
+        let second = cookie.load(&["non-existent_db"]).err().unwrap();
+        assert_eq!(first.desc, "cannot stat `non-existent_file.txt' (No such file or directory)");
+        assert_eq!(first.errno, Some(super::libc::ENOENT));
+        assert_eq!(cookie.error(), Some("could not find any valid magic files!".to_string()));
+        assert!(second.desc.starts_with("could not find any valid magic files!"));
+    }
+
+    #[test]
+    fn last_error() {
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load_default().is_ok());
+        assert!(cookie.last_error().is_none());
+
+        assert!(cookie.file("non-existent_file.txt").is_err());
+        assert!(cookie.file("data/tests/rust-logo-128x128-blk.png").is_ok());
+
+        let e = cookie.last_error().unwrap();
+        assert_eq!(e.message, "cannot stat `non-existent_file.txt' (No such file or directory)");
+        assert_eq!(e.errno.unwrap().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn file_fifo_without_writer() {
+        use std::ffi::CString;
+
+        let path = temp_path("fifo");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { super::libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let cookie = Cookie::open(flags::DEVICES).ok().unwrap();
+        assert!(cookie.load_default().is_ok());
+        let ret = cookie.file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let e = ret.err().unwrap();
+        assert!(e.desc.starts_with("cannot read special file"));
+        assert_eq!(e.kind, super::MagicErrorKind::SpecialFile);
+        assert_eq!(std::io::Error::from(e).kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn file_error_not_retried() {
+        use super::Retry;
+        use std::time::{Duration, Instant};
+
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load_default().is_ok());
+        cookie.set_retry(Retry{attempts: 3, backoff: Duration::from_secs(1),});
+
+        let start = Instant::now();
+        assert!(cookie.file("non-existent_file.txt").is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn close() {
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load_default().is_ok());
+        assert!(cookie.close().is_ok());
+
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load_default().is_ok());
+        assert!(cookie.file("non-existent_file.txt").is_err());
+        assert_eq!(cookie.close().err().unwrap().desc, "cannot stat `non-existent_file.txt' (No such file or directory)");
+    }
+
+    #[test]
+    fn load_with_overlay() {
+        use super::rules::Overlay;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        let overlay = Overlay::new().disable_rule("PNG image data");
+        assert!(cookie.load_with_overlay(&["data/tests/db-images-png", "data/tests/db-python"], &overlay).is_ok());
+        assert!(!cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap().starts_with("PNG image data"));
+
+        let s = b"#!/usr/bin/env python\nprint('Hello, world!')";
+        assert_eq!(cookie.buffer(s).ok().unwrap(), "Python script, ASCII text executable");
+
+        let overlay = Overlay::new().disable_file("data/tests/db-python");
+        assert!(cookie.load_with_overlay(&["data/tests/db-images-png", "data/tests/db-python"], &overlay).is_ok());
+        assert_eq!(cookie.buffer(s).ok().unwrap(), "ASCII text");
+
+        let latin1 = temp_path("overlay-latin1");
+        std::fs::write(&latin1, b"0\tstring\tLATIN\tcaf\xe9 data\n").unwrap();
+        let cookie = Cookie::open(flags::RAW).ok().unwrap();
+        let ret = cookie.load_with_overlay(&[&latin1], &Overlay::new());
+        std::fs::remove_file(&latin1).unwrap();
+        assert!(ret.is_ok());
+        assert_eq!(cookie.buffer_bytes(b"LATIN1").ok().unwrap(), b"caf\xe9 data");
+    }
+
+    #[test]
+    fn create_private_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (first, file) = super::create_private_file("rust-magic-private").unwrap();
+        let mode = file.metadata().unwrap().permissions().mode();
+        let (second, _) = super::create_private_file("rust-magic-private").unwrap();
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn load_recompile_stale() {
+        if !in_temp_cwd("load_recompile_stale") {
+            return;
+        }
+        let source = temp_path("stale");
+        let compiled = temp_path("stale.mgc");
+        std::fs::copy(test_data("db-images-png"), &source).unwrap();
+        let stale = stale_database();
+        std::fs::write(&compiled, &stale).unwrap();
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        let ret = cookie.load(&[&compiled]);
+        assert!(ret.err().unwrap().desc.contains("supports only version"));
+
+        cookie.set_recompile_stale(true);
+        let ret = cookie.load(&[&compiled]);
+        let file = cookie.file(test_data("rust-logo-128x128-blk.png"));
+        std::fs::remove_file(&source).unwrap();
+        std::fs::remove_file(&compiled).unwrap();
+
+        assert!(ret.is_ok());
+        assert_eq!(file.ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+
+        // A source that does not compile leaves the load error in place
+        std::fs::write(&source, "0\tnonsense\tx\tbroken\n").unwrap();
+        std::fs::write(&compiled, &stale).unwrap();
+        let ret = cookie.load(&[&compiled]);
+        let last_error = cookie.last_error();
+        std::fs::remove_file(&source).unwrap();
+        std::fs::remove_file(&compiled).unwrap();
+
+        let e = ret.err().unwrap();
+        assert!(e.desc.contains("supports only version"));
+        assert_eq!(last_error.unwrap().message, e.desc);
+    }
+
+    #[test]
+    fn raw() {
+        let cookie = Cookie::open(flags::MIME_TYPE).ok().unwrap();
+        let cookie = unsafe { Cookie::from_raw(cookie.into_raw()) };
+        assert_eq!(cookie.flags(), flags::MIME_TYPE | flags::ERROR);
+
+        let raw = cookie.into_raw();
+        assert_eq!(unsafe { super::ffi::magic_load(raw, std::ptr::null()) }, 0);
+        assert_eq!(unsafe { super::ffi::magic_errno(raw) }, 0);
+        assert_eq!(unsafe { super::ffi::magic_version() }, super::libmagic_version());
+        let cookie = unsafe { Cookie::from_raw(raw) };
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "image/png");
+        assert_eq!(cookie.flags(), flags::MIME_TYPE | flags::ERROR);
+    }
+
+    #[test]
+    fn flags() {
+        let cookie = Cookie::open(flags::MIME_TYPE).ok().unwrap();
+        assert_eq!(cookie.flags(), flags::MIME_TYPE | flags::ERROR);
+
+        assert!(cookie.set_flags(flags::MIME | flags::SYMLINK).is_ok());
+        assert_eq!(cookie.flags(), flags::MIME | flags::SYMLINK);
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+        assert_eq!(cookie.flags(), flags::ERROR);
+        assert!(cookie.set_flags(cookie.flags() | flags::CHECK).is_ok());
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+        assert_eq!(cookie.flags(), flags::ERROR | flags::CHECK);
+    }
+
+    #[test]
+    fn compress_transp() {
+        let cookie = Cookie::open(flags::COMPRESS).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-extensions"]).is_ok());
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png.gz").ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced (gzip compressed data)");
+
+        assert!(cookie.set_flags(flags::COMPRESS | flags::COMPRESS_TRANSP).is_ok());
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png.gz").ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+    }
+
+    #[test]
+    fn check_flags_supported() {
+        use super::LibmagicVersion;
+
+        assert!(super::check_flags_supported(flags::COMPRESS).is_ok());
+        let supported = LibmagicVersion::current() >= LibmagicVersion{major: 5, minor: 32,};
+        assert_eq!(super::check_flags_supported(flags::COMPRESS_TRANSP).is_ok(), supported);
+
+        let supported = LibmagicVersion::current() >= LibmagicVersion{major: 5, minor: 45,};
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert_eq!(cookie.set_flags(flags::NO_CHECK_JSON | flags::NO_CHECK_SIMH).is_ok(), supported);
+        assert_eq!(Cookie::open(flags::NO_CHECK_SIMH).is_ok(), supported);
+        if !supported {
+            assert_eq!(cookie.flags(), flags::ERROR);
+            assert!(cookie.last_error().unwrap().message.starts_with("flag NO_CHECK_SIMH requires libmagic 5.45 or newer"));
+        }
+    }
+
+    #[test]
+    fn flag_shorthands() {
+        use super::flags::CookieFlags;
+
+        assert_eq!(CookieFlags::mime().compressed().follow_symlinks(), flags::MIME | flags::COMPRESS | flags::SYMLINK);
+        assert_eq!(CookieFlags::description().all_matches().raw(), flags::CONTINUE | flags::RAW);
+        assert_eq!(CookieFlags::mime_type().compressed_transparently(), flags::MIME_TYPE | flags::COMPRESS | flags::COMPRESS_TRANSP);
+        assert!(Cookie::open(CookieFlags::extension().devices().preserve_atime()).is_ok());
+
+        let builtin = CookieFlags::no_check_builtin();
+        assert!(builtin.contains(flags::NO_CHECK_BUILTIN));
+        assert_eq!(builtin.contains(flags::NO_CHECK_JSON), super::libmagic_version() >= 535);
+        assert!(Cookie::open(builtin).is_ok());
+    }
+
+    #[test]
+    fn flag_names() {
+        use super::flags::{CookieFlags, ParseFlagsError};
+
+        assert_eq!("mime,compress,symlink".parse::<CookieFlags>(), Ok(flags::MIME | flags::COMPRESS | flags::SYMLINK));
+        assert_eq!(" Raw , CONTINUE ".parse::<CookieFlags>(), Ok(flags::RAW | flags::CONTINUE));
+        assert_eq!("".parse::<CookieFlags>(), Ok(flags::NONE));
+        assert_eq!("mime,bogus".parse::<CookieFlags>(), Err(ParseFlagsError{name: "bogus".to_string(),}));
+
+        assert_eq!((flags::MIME | flags::COMPRESS).to_string(), "compress,mime_type,mime_encoding");
+        assert_eq!(flags::NONE.to_string(), "none");
+        let all = flags::NO_CHECK_BUILTIN | flags::EXTENSION | flags::DEBUG;
+        assert_eq!(all.to_string().parse::<CookieFlags>(), Ok(all));
+    }
+
+    #[test]
+    fn flag_conflicts() {
+        let e = Cookie::open(flags::MIME_TYPE | flags::APPLE).err().unwrap();
+        assert_eq!(e.desc, "flags MIME and APPLE cannot be combined");
+
+        let cookie = Cookie::open(flags::CONTINUE).ok().unwrap();
+        let e = cookie.set_flags(flags::CONTINUE | flags::MIME_ENCODING).err().unwrap();
+        assert_eq!(e.desc, "flag CONTINUE cannot be combined with MIME");
+        assert_eq!(cookie.flags(), flags::CONTINUE | flags::ERROR);
+
+        assert!(cookie.set_flags(flags::MIME | flags::COMPRESS).is_ok());
+        assert!(cookie.set_flags(flags::CONTINUE | flags::EXTENSION).is_ok());
+    }
+
+    #[test]
+    fn params() {
+        use super::Param;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        for &param in &[Param::IndirMax, Param::NameMax, Param::ElfPhnumMax, Param::ElfShnumMax,
+                        Param::ElfNotesMax, Param::RegexMax, Param::BytesMax, Param::EncodingMax] {
+            assert!(cookie.get_param(param).ok().unwrap() > 0);
+        }
+
+        assert!(cookie.set_param(Param::BytesMax, 4096).is_ok());
+        assert_eq!(cookie.get_param(Param::BytesMax).ok().unwrap(), 4096);
+    }
+
+    #[test]
+    fn max_bytes() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+
+        assert!(cookie.set_max_bytes(0).is_err());
+        assert!(cookie.set_max_bytes(4).is_ok());
+        assert_eq!(cookie.max_bytes().ok().unwrap(), 4);
+        assert!(!cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap().starts_with("PNG image data"));
+
+        assert!(cookie.set_max_bytes(1024).is_ok());
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+    }
+
+    #[test]
+    fn reload() {
+        let mut cookie = Cookie::open(flags::MIME_TYPE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+        assert!(cookie.set_max_bytes(1024).is_ok());
+
+        assert!(cookie.reload(&["non-existent_file.txt"]).is_err());
+        assert!(cookie.last_error().is_some());
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "image/png");
+
+        assert!(cookie.reload(&["data/tests/db-python"]).is_ok());
+        assert_eq!(cookie.max_bytes().ok().unwrap(), 1024);
+        assert_eq!(cookie.buffer(b"#!/usr/bin/env python\n").ok().unwrap(), "text/x-python");
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "application/octet-stream");
+
+        assert!(cookie.set_flags(flags::NONE).is_ok());
+        assert!(cookie.reload(&["data/tests/db-images-png"]).is_ok());
+        assert_eq!(cookie.flags(), flags::NONE);
+        assert!(cookie.file("non-existent_file.txt").ok().unwrap().starts_with("cannot open"));
+    }
+
+    #[test]
+    fn from_file() {
+        assert!(super::from_file("data/tests/rust-logo-128x128-blk.png").ok().unwrap().starts_with("PNG image data, 128 x 128"));
+        assert!(super::from_file("non-existent_file.txt").is_err());
+        assert_eq!(super::from_buffer(b"").ok().unwrap(), "empty");
+    }
+
+    #[test]
+    fn global() {
+        assert!(super::set_global_flags(flags::MIME_TYPE | flags::APPLE).is_err());
+        assert!(super::set_global_flags(flags::MIME_TYPE).is_ok());
+        assert!(super::global().file("data/tests/rust-logo-128x128-blk.png").ok().unwrap().starts_with("image/png"));
+        assert!(std::rc::Rc::ptr_eq(&super::global(), &super::try_global().ok().unwrap()));
+
+        assert!(super::set_global_flags(flags::NONE).is_ok());
+        assert!(super::global().file("data/tests/rust-logo-128x128-blk.png").ok().unwrap().starts_with("PNG image data, 128 x 128"));
+        assert!(super::set_global_flags(flags::MIME_TYPE | flags::APPLE).is_err());
+        assert_eq!(super::global().flags(), flags::NONE);
+
+        let other = std::thread::spawn(|| {
+            assert!(super::set_global_flags(flags::MIME_TYPE | flags::APPLE).is_err());
+            super::global().file("data/tests/rust-logo-128x128-blk.png").ok().unwrap()
+        });
+        assert!(other.join().unwrap().starts_with("PNG image data, 128 x 128"));
+    }
+
+    #[test]
+    fn default() {
+        let cookie = Cookie::try_default().ok().unwrap();
+        assert_eq!(cookie.flags(), flags::ERROR);
+        assert!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap().starts_with("PNG image data, 128 x 128"));
+
+        #[derive(Default)]
+        struct Scanner { cookie: Cookie }
+        let scanner = Scanner::default();
+        assert_eq!(scanner.cookie.buffer(b"").ok().unwrap(), "empty");
+    }
+
+    #[test]
+    fn builder() {
+        use super::Param;
+
+        let cookie = Cookie::builder()
+            .flags(flags::MIME_TYPE)
+            .database("data/tests/db-images-png")
+            .database("data/tests/db-python")
+            .param(Param::BytesMax, 1 << 20)
+            .build().ok().unwrap();
+        assert_eq!(cookie.flags(), flags::MIME_TYPE | flags::ERROR);
+        assert_eq!(cookie.max_bytes().ok().unwrap(), 1 << 20);
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "image/png");
+        assert_eq!(cookie.buffer(b"#!/usr/bin/env python\n").ok().unwrap(), "text/x-python");
+
+        assert!(Cookie::builder().database("non-existent_file.txt").build().is_err());
+        assert!(Cookie::builder().flags(flags::MIME_TYPE | flags::APPLE).build().is_err());
+    }
+
+    #[test]
+    fn chaining() {
+        use super::Param;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        let file = cookie.set_flags(flags::MIME_TYPE)
+            .and_then(|cookie| cookie.set_param(Param::BytesMax, 1 << 20))
+            .and_then(|cookie| cookie.load(&["data/tests/db-images-png"]))
+            .and_then(|cookie| cookie.file("data/tests/rust-logo-128x128-blk.png"));
+        assert_eq!(file.ok().unwrap(), "image/png");
+
+        assert!(cookie.set_flags(flags::NONE).and_then(|cookie| cookie.load(&["non-existent_file.txt"])).is_err());
+        assert_eq!(cookie.flags(), flags::NONE);
+    }
+
+    #[test]
+    fn debug() {
+        let cookie = Cookie::open(flags::MIME_TYPE).ok().unwrap();
+        assert_eq!(format!("{:?}", cookie), "Cookie { flags: MIME_TYPE | ERROR, databases: none }");
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+        assert_eq!(format!("{:?}", cookie), "Cookie { flags: MIME_TYPE | ERROR, databases: [\"data/tests/db-images-png\"] }");
+        assert!(cookie.load(&["non-existent_file.txt"]).is_err());
+        assert_eq!(format!("{:?}", cookie), "Cookie { flags: MIME_TYPE | ERROR, databases: none }");
+        assert!(cookie.load_default().is_ok());
+        assert_eq!(format!("{:?}", cookie), "Cookie { flags: MIME_TYPE | ERROR, databases: default }");
+
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+        assert!(cookie.check(&["data/tests/db-python"]).is_ok());
+        assert_eq!(format!("{:?}", cookie), "Cookie { flags: MIME_TYPE | ERROR, databases: none }");
+        assert!(cookie.try_clone().ok().unwrap().file("data/tests/rust-logo-128x128-blk.png").is_err());
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+        assert!(cookie.compile(&["non-existent_file.txt"]).is_err());
+        assert_eq!(format!("{:?}", cookie), "Cookie { flags: MIME_TYPE | ERROR, databases: none }");
+    }
+
+    #[test]
+    fn try_clone() {
+        use super::{Param, Decoding};
+
+        let cookie = Cookie::open(flags::MIME_TYPE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+        assert!(cookie.set_param(Param::BytesMax, 1 << 20).is_ok());
+        cookie.set_decoding(Decoding::Lossy);
+
+        let clone = cookie.try_clone().ok().unwrap();
+        assert_eq!(format!("{:?}", clone), format!("{:?}", cookie));
+        assert_eq!(clone.max_bytes().ok().unwrap(), 1 << 20);
+        assert_eq!(clone.decoding.get(), Decoding::Lossy);
+        let clone = std::thread::spawn(move || {
+            assert_eq!(clone.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "image/png");
+            clone
+        }).join().unwrap();
+
+        assert!(clone.set_flags(flags::NONE).is_ok());
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "image/png");
+        assert_eq!(clone.try_clone().ok().unwrap().flags(), flags::NONE);
+    }
+
+    #[test]
+    fn scoped_flags() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+        let flags = cookie.flags();
+
+        let mime = cookie.with_flags(flags::MIME_TYPE, |c| c.file("data/tests/rust-logo-128x128-blk.png"));
+        assert_eq!(mime.ok().unwrap(), "image/png");
+        assert!(cookie.with_flags(flags::MIME_TYPE | flags::ERROR, |c| c.file("non-existent_file.txt")).is_err());
+        assert_eq!(cookie.flags(), flags);
+
+        {
+            let scoped = cookie.scoped_flags(flags::MIME_TYPE).ok().unwrap();
+            assert_eq!(scoped.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "image/png");
+        }
+        assert_eq!(cookie.flags(), flags);
+        assert!(cookie.scoped_flags(flags::MIME_TYPE | flags::APPLE).is_err());
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+    }
+
+    #[test]
+    fn shared() {
+        use super::SharedCookie;
+        use std::sync::Arc;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+        let cookie = std::thread::spawn(move || cookie).join().unwrap();
+
+        let shared = Arc::new(SharedCookie::new(cookie));
+        let threads: Vec<_> = (0..4).map(|_| {
+            let shared = shared.clone();
+            std::thread::spawn(move || shared.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap())
+        }).collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+        }
+
+        assert!(shared.set_flags(flags::MIME_TYPE).is_ok());
+        assert_eq!(shared.lock().file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "image/png");
+        assert!(Arc::try_unwrap(shared).ok().unwrap().into_inner().close().is_ok());
+    }
+
+    #[test]
+    fn shared_reload() {
+        use super::SharedCookie;
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::sync::{Arc, mpsc};
+        use std::time::Duration;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-python"]).is_ok());
+        let shared = Arc::new(SharedCookie::new(cookie));
+
+        // Loading from a FIFO blocks until something writes the database into it
+        let fifo = temp_path("reload");
+        let path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        let reloading = {
+            let (shared, fifo) = (shared.clone(), fifo.clone());
+            std::thread::spawn(move || shared.reload(&[&fifo]))
+        };
+
+        // Opening the writing end without blocking only works once the reload opened the other one
+        let mut writer = loop {
+            match std::fs::OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(&fifo) {
+                Ok(writer) => break writer,
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let s = b"#!/usr/bin/env python\nprint('Hello, world!')";
+        let user = {
+            let shared = shared.clone();
+            std::thread::spawn(move || sender.send(shared.buffer(s).ok().unwrap()).unwrap())
+        };
+        let during = receiver.recv_timeout(Duration::from_secs(10));
+        writer.write_all(&std::fs::read("data/tests/db-images-png").unwrap()).unwrap();
+        drop(writer);
+        let reloaded = reloading.join().unwrap();
+        user.join().unwrap();
+        std::fs::remove_file(&fifo).unwrap();
+
+        assert_eq!(during.unwrap(), "Python script, ASCII text executable");
+        assert!(reloaded.is_ok());
+        assert_eq!(shared.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+    }
+
+    #[test]
+    fn compile() {
+        if !in_temp_cwd("compile") {
+            return;
+        }
+        use std::path::PathBuf;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        let source = temp_path("compile");
+        std::fs::copy(test_data("db-python"), &source).unwrap();
+        let compiled = cookie.compile(&[&source]).ok().unwrap();
+        std::fs::remove_file(&source).unwrap();
+
+        let expected = format!("{}.mgc", source.file_name().unwrap().to_str().unwrap());
+        assert_eq!(compiled, vec![PathBuf::from(expected)]);
+        assert!(compiled[0].is_file());
+        std::fs::remove_file(&compiled[0]).unwrap();
+
+        assert!(cookie.compile(&["non-existent_file.txt"]).is_err());
+
+        let source = temp_path("compile-to");
+        std::fs::copy(test_data("db-python"), &source).unwrap();
+        let unrelated = super::compiled_name(&source).unwrap();
+        std::fs::write(&unrelated, "precious").unwrap();
+        let e = cookie.compile_to(&source, source.with_extension("mgc")).err().unwrap();
+        let kept = std::fs::read_to_string(&unrelated).unwrap();
+        std::fs::remove_file(&unrelated).unwrap();
+        std::fs::remove_file(&source).unwrap();
+        assert_eq!(kept, "precious");
+        assert_eq!(e.io_error_kind(), Some(std::io::ErrorKind::AlreadyExists));
+        assert_eq!(super::compiled_name(&PathBuf::from("a/b.mgc")), Some(PathBuf::from("b.mgc")));
+    }
+
+    #[test]
+    fn compile_detailed() {
+        if !in_temp_cwd("compile_detailed") {
+            return;
+        }
+        use std::path::PathBuf;
+        use super::CompileError;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        let source = temp_path("compile-detailed");
+        std::fs::write(&source, "0\tstring\tRMGC\tok\n0\tbogus\tx\tbad\n").unwrap();
+        let ret = cookie.compile_detailed(&[&source]);
+        std::fs::remove_file(&source).unwrap();
+
+        let failure = ret.err().unwrap();
+        assert_eq!(failure.error.desc, "could not find any valid magic files!");
+        assert_eq!(failure.errors, vec![CompileError{path: source, line: 2, message: "type `bogus\tx\tbad' invalid".to_string(),}]);
+
+        let compiled = cookie.compile_detailed(&[test_data("db-python")]).ok().unwrap();
+        assert_eq!(compiled, vec![PathBuf::from("db-python.mgc")]);
+        std::fs::remove_file(&compiled[0]).unwrap();
+    }
+
+    #[test]
+    fn load_buffers() {
+        if !in_temp_cwd("load_buffers") {
+            return;
+        }
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        let source = temp_path("buffers");
+        std::fs::copy(test_data("db-images-png"), &source).unwrap();
+        let compiled = source.with_extension("mgc");
+        assert!(cookie.compile_to(&source, &compiled).is_ok());
+        let db = std::fs::read(&compiled).unwrap();
+        std::fs::remove_file(&compiled).unwrap();
+        std::fs::remove_file(&source).unwrap();
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load_buffers(&[&db]).is_ok());
+        assert_eq!(cookie.file(test_data("rust-logo-128x128-blk.png")).ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+        assert_eq!(format!("{:?}", cookie), "Cookie { flags: ERROR, databases: 1 buffer(s) }");
+        let clone = cookie.try_clone().ok().unwrap();
+        assert_eq!(clone.file(test_data("rust-logo-128x128-blk.png")).ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+
+        assert!(cookie.load_buffers(&[b"not a compiled database"]).is_err());
+    }
+
+    #[test]
+    fn entries() {
+        use super::MagicEntry;
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        let entries = cookie.entries(&["data/tests/db-images-png"]).ok().unwrap();
+        assert_eq!(entries, vec![MagicEntry{
+            strength: 110,
+            line: 1,
+            message: "PNG image data".to_string(),
+            mime: Some("image/png".to_string()),
+            text: false,
+        }]);
+
+        let entries = cookie.entries(&["data/tests/db-python"]).ok().unwrap();
+        assert_eq!(entries.len(), 4);
+        assert!(entries.iter().all(|entry| entry.text && entry.mime == Some("text/x-python".to_string())));
+        assert_eq!(entries[0].line, 3);
+
+        assert!(cookie.entries(&["non-existent_file.txt"]).is_err());
+    }
+
+    #[test]
+    fn parse_listing() {
+        let entries = super::parse_listing("Set 0:\nBinary patterns:\nStrength =  40@12: [a] b \nStrength = 99@x: bad\nText patterns:\nStrength = 7@2: c [text/x-c] []\n");
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].message.as_str(), entries[0].mime.clone(), entries[0].text), ("[a] b ", None, false));
+        assert_eq!((entries[1].message.as_str(), entries[1].mime.clone(), entries[1].text), ("c [text/x-c]", None, true));
+    }
+
+    #[test]
+    fn load_default() {
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load::<&str>(&[]).is_ok());
+    }
+
+    #[test]
+    fn load_default_database() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        assert!(cookie.load_default().is_ok());
+        assert!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap().starts_with("PNG image data"));
+    }
+
+    #[test]
+    fn load_error() {
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+
+        let e = cookie.load(&["non-existent_file.txt"]).err().unwrap();
+        assert_eq!(e.desc, "could not find any valid magic files! (cannot access `non-existent_file.txt': No such file or directory (os error 2))");
+        assert_eq!(e.errno, Some(super::libc::ENOENT));
+        assert_eq!(cookie.last_error().unwrap().message, e.desc);
+
+        let stale = temp_path("load-error.mgc");
+        std::fs::write(&stale, stale_database()).unwrap();
+        let ret = cookie.load(&[&stale]);
+        std::fs::remove_file(&stale).unwrap();
+        assert!(ret.err().unwrap().desc.contains("supports only version"));
+    }
+
+    #[test]
+    fn load_all() {
+        let cookie = Cookie::open(flags::MIME_TYPE).ok().unwrap();
+        assert!(cookie.load_all(vec!["data/tests/db-images-png"]).is_ok());
+
+        let e = cookie.load_all(["data/tests/db-python", "non-existent_file.txt"]).err().unwrap();
+        assert_eq!(e.desc, "cannot load `non-existent_file.txt' (No such file or directory (os error 2))");
+        assert_eq!(e.errno, Some(super::libc::ENOENT));
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "image/png");
+
+        let stale = temp_path("load-all.mgc");
+        std::fs::write(&stale, stale_database()).unwrap();
+        let ret = cookie.load_all(std::iter::once(&stale));
+        std::fs::remove_file(&stale).unwrap();
+        assert!(ret.err().unwrap().desc.ends_with(&format!("(while loading `{}')", stale.display())));
+    }
+
+    #[test]
+    fn capture_with_child() {
+        use std::process::Command;
+        use std::time::Instant;
+
+        let start = Instant::now();
+        let (child, output) = super::capture_fd(super::libc::STDERR_FILENO, || {
+            let child = Command::new("sleep").arg("10").spawn();
+            unsafe { super::libc::write(super::libc::STDERR_FILENO, b"captured".as_ptr() as *const super::libc::c_void, 8) };
+            child
+        }).unwrap();
+        let mut child = child.unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(output, b"captured");
+    }
+
+    #[test]
+    fn warning_handler() {
+        use std::sync::{Arc, Mutex};
+
+        let cookie = Cookie::open(flags::NONE).ok().unwrap();
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let collected = warnings.clone();
+        cookie.set_warning_handler(move |warning| collected.lock().unwrap().push(warning.to_string()));
+
+        assert!(cookie.load(&["data/tests/db-python"]).is_ok());
+        assert!(warnings.lock().unwrap().is_empty());
+        assert!(cookie.check(&["data/tests/db-python"]).is_ok());
+        assert_eq!(warnings.lock().unwrap()[0], "Warning: using regular magic file `data/tests/db-python'");
+
+        warnings.lock().unwrap().clear();
+        assert!(cookie.set_flags(flags::DEBUG).is_ok());
+        assert_eq!(cookie.buffer(b"#!/usr/bin/env python\n").ok().unwrap(), "Python script, ASCII text executable");
+        assert!(warnings.lock().unwrap().iter().any(|line| line.starts_with("[try softmagic")));
+    }
+
+    #[test]
+    fn load_one() {
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load(&[
+                                    "data/tests/db-images-png"
+                                ]).is_ok());
     }
 
     #[test]
-    // TODO: This should not really fail
-    #[should_panic(expected = "not yet implemented")]
     fn load_multiple() {
         let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
-        assert!(cookie.load(&vec![
+        assert!(cookie.load(&[
                                 "data/tests/db-images-png",
                                 "data/tests/db-python",
                             ]).is_ok());
     }
 
+    #[test]
+    fn load_many() {
+        use std::path::PathBuf;
+
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load_many(vec![PathBuf::from("data/tests/db-images-png"), PathBuf::from("data/tests/db-python")]).is_ok());
+        assert_eq!(cookie.file("data/tests/rust-logo-128x128-blk.png").ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+        assert_eq!(cookie.buffer(b"#!/usr/bin/env python\n").ok().unwrap(), "Python script, ASCII text executable");
+
+        let ret = cookie.load_many(vec![PathBuf::from("data/tests/db-python"), PathBuf::from("data/tests/a:b")]);
+        let e = ret.err().unwrap();
+        assert_eq!(e.desc, "path `data/tests/a:b' contains the database separator `:'");
+        assert_eq!(e.kind, super::MagicErrorKind::InvalidPath);
+    }
+
+    #[test]
+    fn file_nul_byte() {
+        use super::MagicErrorKind;
+
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load_default().is_ok());
+
+        let e = cookie.file("data/tests/\0rust-logo-128x128-blk.png").err().unwrap();
+        assert_eq!(e.kind, MagicErrorKind::InvalidPath);
+        assert!(e.desc.contains("NUL byte"));
+        assert_eq!(cookie.load(&["data/tests/\0db-python"]).err().unwrap().kind, MagicErrorKind::InvalidPath);
+        assert_eq!(cookie.file("non-existent_file.txt").err().unwrap().kind, MagicErrorKind::Other);
+    }
+
+    #[test]
+    fn file_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let cookie = Cookie::open(flags::NONE | flags::ERROR).ok().unwrap();
+        assert!(cookie.load(&["data/tests/db-images-png"]).is_ok());
+
+        let path = temp_path(OsStr::from_bytes(b"caf\xe9"));
+        std::fs::copy("data/tests/rust-logo-128x128-blk.png", &path).unwrap();
+        let ret = cookie.file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(ret.ok().unwrap(), "PNG image data, 128 x 128, 8-bit/color RGBA, non-interlaced");
+    }
+
+    #[test]
+    fn libmagic_version() {
+        use super::LibmagicVersion;
+
+        assert!(super::libmagic_version() >= 500);
+        assert_eq!(LibmagicVersion::current(), LibmagicVersion::from_int(super::libmagic_version()));
+        assert_eq!(LibmagicVersion::from_int(544), LibmagicVersion{major: 5, minor: 44,});
+        assert_eq!(LibmagicVersion::from_int(505).to_string(), "5.05");
+        assert!(LibmagicVersion::from_int(539) < LibmagicVersion::from_int(544));
+    }
+
     #[test]
     fn version() {
         assert!(regex::is_match(r"\d+\.\d+.\d+", super::version()).ok().unwrap());
@@ -0,0 +1,669 @@
+//! Parsing of magic(5) source files
+//!
+//! This is independent of `libmagic` itself and works on the textual
+//! database format, e.g. to lint or reformat custom rule sets before
+//! handing them to `Cookie::compile`.
+//!
+//! ```
+//! use magic::rules::{self, Item};
+//!
+//! let source = "0 string \\x89PNG PNG image data\n!:mime image/png\n>16 belong x \\b, %d x\n";
+//! let items = rules::parse(source).ok().unwrap();
+//!
+//! match items[0] {
+//!     Item::Entry(ref entry) => assert_eq!(entry.mime, Some("image/png".to_string())),
+//!     _ => unreachable!(),
+//! }
+//! ```
+
+use std::error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+
+/// Comparison done by a magic entry's test
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Operator {
+    /// `=`, also used when no operator is given
+    Equal,
+    /// `!`
+    NotEqual,
+    /// `<`
+    LessThan,
+    /// `>`
+    GreaterThan,
+    /// `&`, all bits of the value are set
+    AllSet,
+    /// `^`, at least one bit of the value is clear
+    AnyClear,
+    /// `~`, the value is negated before comparing
+    Negate,
+    /// `x`, any value matches
+    Any,
+}
+
+impl Operator {
+    fn to_char(self) -> Option<char> {
+        match self {
+            Operator::Equal => Some('='),
+            Operator::NotEqual => Some('!'),
+            Operator::LessThan => Some('<'),
+            Operator::GreaterThan => Some('>'),
+            Operator::AllSet => Some('&'),
+            Operator::AnyClear => Some('^'),
+            Operator::Negate => Some('~'),
+            Operator::Any => None,
+        }
+    }
+
+    fn from_char(c: char) -> Option<Operator> {
+        match c {
+            '=' => Some(Operator::Equal),
+            '!' => Some(Operator::NotEqual),
+            '<' => Some(Operator::LessThan),
+            '>' => Some(Operator::GreaterThan),
+            '&' => Some(Operator::AllSet),
+            '^' => Some(Operator::AnyClear),
+            '~' => Some(Operator::Negate),
+            _ => None,
+        }
+    }
+}
+
+
+/// Arithmetic applied to the default strength of an entry by `!:strength`
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum StrengthOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+/// A `!:strength` annotation, e.g. `+ 20`
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Strength {
+    pub op: StrengthOp,
+    pub value: u32,
+}
+
+impl fmt::Display for Strength {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self.op {
+            StrengthOp::Add => '+',
+            StrengthOp::Subtract => '-',
+            StrengthOp::Multiply => '*',
+            StrengthOp::Divide => '/',
+        };
+        write!(f, "{}{}", op, self.value)
+    }
+}
+
+
+/// A single magic entry (test line) together with its `!:` annotations
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Entry {
+    /// Continuation level, i.e. the number of leading `>`
+    pub level: usize,
+    /// Offset expression, e.g. `0`, `&4` or `(4.l+2)`
+    pub offset: String,
+    /// Type including any mask or modifiers, e.g. `belong&0xff` or `search/1/w`
+    pub kind: String,
+    pub operator: Operator,
+    /// Test value as written in the source, escapes are kept as is
+    pub value: String,
+    /// Message printed on a match, may be empty
+    pub message: String,
+    /// `!:mime`
+    pub mime: Option<String>,
+    /// `!:strength`
+    pub strength: Option<Strength>,
+    /// `!:ext`, a `/`-separated list of extensions
+    pub extensions: Option<String>,
+    /// `!:apple`, the Apple creator and type
+    pub apple: Option<String>,
+}
+
+
+impl Entry {
+    fn test(&self) -> String {
+        match self.operator.to_char() {
+            None => "x".to_string(),
+            // `=` is implied unless the value could be mistaken for another operator
+            Some('=') if self.value != "x" && !self.value.starts_with(|c| Operator::from_char(c).is_some()) => {
+                self.value.clone()
+            },
+            Some(op) => format!("{}{}", op, self.value),
+        }
+    }
+}
+
+/// Emits the entry line followed by one line per annotation
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}\t{}\t{}", ">".repeat(self.level), self.offset, self.kind, self.test())?;
+        if !self.message.is_empty() {
+            write!(f, "\t{}", self.message)?;
+        }
+        if let Some(ref mime) = self.mime {
+            write!(f, "\n!:mime\t{}", mime)?;
+        }
+        if let Some(ref apple) = self.apple {
+            write!(f, "\n!:apple\t{}", apple)?;
+        }
+        if let Some(ref extensions) = self.extensions {
+            write!(f, "\n!:ext\t{}", extensions)?;
+        }
+        if let Some(ref strength) = self.strength {
+            write!(f, "\n!:strength\t{}", strength)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// A line of a magic source file
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Item {
+    /// A comment line without its leading `#`
+    Comment(String),
+    /// An empty line
+    Blank,
+    Entry(Entry),
+}
+
+
+/// Emits the item as magic source, see `to_source`
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Item::Comment(ref comment) => write!(f, "#{}", comment),
+            Item::Blank => Ok(()),
+            Item::Entry(ref entry) => write!(f, "{}", entry),
+        }
+    }
+}
+
+
+/// Error returned when a magic source file can not be parsed
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseError {
+    /// 1-based line number
+    pub line: usize,
+    pub desc: String,
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        "invalid magic source"
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.desc)
+    }
+}
+
+
+fn is_blank(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+/// Splits off the next whitespace-delimited field, honouring `\`-escapes
+fn next_field(s: &str) -> (&str, &str) {
+    let s = s.trim_start_matches(is_blank);
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if is_blank(c) {
+            return (&s[..i], &s[i..]);
+        }
+    }
+    (s, "")
+}
+
+fn parse_strength(s: &str) -> Option<Strength> {
+    let s = s.trim();
+    let op = match s.chars().next() {
+        Some('+') => StrengthOp::Add,
+        Some('-') => StrengthOp::Subtract,
+        Some('*') => StrengthOp::Multiply,
+        Some('/') => StrengthOp::Divide,
+        _ => return None,
+    };
+    s[1..].trim().parse().ok().map(|value| Strength{op, value,})
+}
+
+fn parse_entry(line: &str) -> Result<Entry, String> {
+    let level = line.chars().take_while(|&c| c == '>').count();
+    let (offset, rest) = next_field(&line[level..]);
+    let (kind, rest) = next_field(rest);
+    let (test, rest) = next_field(rest);
+    if offset.is_empty() || kind.is_empty() || test.is_empty() {
+        return Err("expected offset, type and test".to_string());
+    }
+
+    let (operator, value) = if test == "x" {
+        (Operator::Any, "")
+    } else {
+        match test.chars().next().and_then(Operator::from_char) {
+            Some(operator) if test.len() > 1 => (operator, &test[1..]),
+            _ => (Operator::Equal, test),
+        }
+    };
+
+    Ok(Entry{
+        level,
+        offset: offset.to_string(),
+        kind: kind.to_string(),
+        operator,
+        value: value.to_string(),
+        message: rest.trim_start_matches(is_blank).to_string(),
+        mime: None,
+        strength: None,
+        extensions: None,
+        apple: None,
+    })
+}
+
+fn annotate(entry: &mut Entry, line: &str) -> Result<(), String> {
+    let (key, value) = next_field(&line[2..]);
+    let value = value.trim();
+    match key {
+        "mime" => entry.mime = Some(value.to_string()),
+        "ext" => entry.extensions = Some(value.to_string()),
+        "apple" => entry.apple = Some(value.to_string()),
+        "strength" => match parse_strength(value) {
+            Some(strength) => entry.strength = Some(strength),
+            None => return Err(format!("invalid strength `{}'", value)),
+        },
+        _ => return Err(format!("unknown annotation `!:{}'", key)),
+    }
+    Ok(())
+}
+
+/// Parses the contents of a magic(5) source file
+pub fn parse(source: &str) -> Result<Vec<Item>, ParseError> {
+    let mut items = Vec::new();
+    // Index into `items` of the entry that `!:` lines annotate
+    let mut last_entry = None;
+
+    for (i, line) in source.lines().enumerate() {
+        let error = |desc| ParseError{line: i + 1, desc,};
+
+        if line.trim().is_empty() {
+            items.push(Item::Blank);
+        } else if let Some(comment) = line.strip_prefix('#') {
+            items.push(Item::Comment(comment.to_string()));
+        } else if line.starts_with("!:") {
+            match last_entry.map(|index| &mut items[index]) {
+                Some(&mut Item::Entry(ref mut entry)) => annotate(entry, line).map_err(error)?,
+                _ => return Err(error("annotation without an entry".to_string())),
+            }
+        } else {
+            items.push(Item::Entry(parse_entry(line).map_err(error)?));
+            last_entry = Some(items.len() - 1);
+        }
+    }
+
+    Ok(items)
+}
+
+/// A parsed magic source file
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Database {
+    pub items: Vec<Item>,
+}
+
+impl Database {
+    /// Parses a magic source file, see `parse`
+    pub fn parse(source: &str) -> Result<Database, ParseError> {
+        parse(source).map(|items| Database{items,})
+    }
+
+    /// Groups the entries into rules, each being a top-level entry followed by its continuations
+    fn rules(&self) -> Vec<Vec<&Entry>> {
+        let mut rules: Vec<Vec<&Entry>> = Vec::new();
+        for entry in self.entries() {
+            match rules.last_mut() {
+                Some(ref mut rule) if entry.level > 0 => rule.push(entry),
+                _ => rules.push(vec![entry]),
+            }
+        }
+        rules
+    }
+
+    /// Returns a database with only the rules needed to detect the given MIME types
+    ///
+    /// A rule is kept if it or one of its continuations is annotated with one
+    /// of `mimes`. Named rules (`name`) referenced with `use` by kept rules are
+    /// kept as well. Comments are dropped. The result can be written out with
+    /// `to_source` and compiled with `Cookie::compile`.
+    pub fn subset(&self, mimes: &[&str]) -> Database {
+        let rules = self.rules();
+        let name_of = |rule: &Vec<&Entry>| if rule[0].kind == "name" { Some(rule[0].value.clone()) } else { None };
+
+        let mut keep: Vec<bool> = rules.iter().map(|rule| {
+            rule.iter().any(|entry| entry.mime.as_ref().is_some_and(|mime| mimes.contains(&mime.as_str())))
+        }).collect();
+
+        // Pull in named rules until no kept rule uses a name that is not kept yet
+        loop {
+            let used: Vec<String> = rules.iter().zip(&keep)
+                .filter(|&(_, &kept)| kept)
+                .flat_map(|(rule, _)| rule.iter().filter(|entry| entry.kind == "use"))
+                .map(|entry| entry.value.trim_start_matches("\\^").to_string())
+                .collect();
+            let mut changed = false;
+            for (i, rule) in rules.iter().enumerate() {
+                if !keep[i] && name_of(rule).is_some_and(|name| used.contains(&name)) {
+                    keep[i] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut items = Vec::new();
+        for (rule, _) in rules.iter().zip(&keep).filter(|&(_, &kept)| kept) {
+            if !items.is_empty() {
+                items.push(Item::Blank);
+            }
+            items.extend(rule.iter().map(|&entry| Item::Entry(entry.clone())));
+        }
+        Database{items,}
+    }
+
+    /// Iterates over all entries, skipping comments and blank lines
+    pub fn entries(&self) -> Entries<'_> {
+        Entries{items: self.items.iter(),}
+    }
+
+    /// Returns the entries annotated with `!:mime mime`
+    pub fn rules_for_mime(&self, mime: &str) -> Vec<&Entry> {
+        self.entries().filter(|entry| entry.mime.as_deref() == Some(mime)).collect()
+    }
+
+    /// Returns the entries whose message satisfies `predicate`
+    ///
+    /// A description reported by `libmagic` concatenates the messages of an
+    /// entry and its matching continuations, so this tests messages of single
+    /// entries:
+    ///
+    /// ```
+    /// extern crate magic;
+    /// extern crate regex;
+    ///
+    /// # fn main() {
+    /// let db = magic::rules::Database::parse("0 string \\x89PNG PNG image data\n>25 byte 6 \\b/color RGBA,\n").ok().unwrap();
+    /// let re = regex::Regex::new("RGBA").unwrap();
+    /// let entries = db.rules_matching_description(|message| re.is_match(message));
+    /// assert_eq!(entries[0].offset, "25");
+    /// # }
+    /// ```
+    pub fn rules_matching_description<F: Fn(&str) -> bool>(&self, predicate: F) -> Vec<&Entry> {
+        self.entries().filter(|entry| predicate(&entry.message)).collect()
+    }
+}
+
+/// Rules and source files to leave out when loading a database
+///
+/// Used with `Cookie::load_with_overlay` to suppress noisy entries without
+/// editing the installed database.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Overlay {
+    files: Vec<PathBuf>,
+    rules: Vec<String>,
+}
+
+impl Overlay {
+    pub fn new() -> Overlay {
+        Overlay::default()
+    }
+
+    /// Skips the source file at `path`, e.g. one file of a `Magdir` directory
+    pub fn disable_file<P: AsRef<Path>>(mut self, path: P) -> Overlay {
+        self.files.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Skips every rule whose top-level message or `name` equals `rule`
+    pub fn disable_rule(mut self, rule: &str) -> Overlay {
+        self.rules.push(rule.to_string());
+        self
+    }
+
+    /// Returns whether the source file at `path` should be loaded
+    pub fn allows_file(&self, path: &Path) -> bool {
+        !self.files.iter().any(|file| file == path)
+    }
+
+    /// Returns `db` without the disabled rules
+    pub fn apply(&self, db: &Database) -> Database {
+        let mut items = Vec::new();
+        for rule in db.rules() {
+            let disabled = self.rules.iter().any(|disabled| {
+                *disabled == rule[0].message || (rule[0].kind == "name" && *disabled == rule[0].value)
+            });
+            if !disabled {
+                items.extend(rule.iter().map(|&entry| Item::Entry(entry.clone())));
+            }
+        }
+        Database{items,}
+    }
+}
+
+
+/// Iterator over the entries of a `Database`
+pub struct Entries<'a> {
+    items: std::slice::Iter<'a, Item>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = &'a Entry;
+
+    fn next(&mut self) -> Option<&'a Entry> {
+        for item in &mut self.items {
+            if let Item::Entry(ref entry) = *item {
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
+
+
+/// Emits magic source for `items`, one line per item and annotation
+///
+/// Parsing the result yields `items` again, so this can be used to write
+/// back rules after editing them, e.g. adding `mime` or changing `strength`.
+pub fn to_source(items: &[Item]) -> String {
+    let mut source = String::new();
+    for item in items {
+        source.push_str(&format!("{}\n", item));
+    }
+    source
+}
+
+/// Normalizes the layout of a magic(5) source file
+///
+/// Entries and annotations are written with tab-separated columns,
+/// operators are only spelled out where needed and runs of blank lines
+/// are collapsed into one. Comments only lose trailing whitespace and
+/// messages are kept as is.
+pub fn format(source: &str) -> Result<String, ParseError> {
+    let mut items = parse(source)?;
+
+    let mut previous_blank = true;
+    items.retain(|item| {
+        let blank = *item == Item::Blank;
+        let keep = !(blank && previous_blank);
+        previous_blank = blank;
+        keep
+    });
+    if items.last() == Some(&Item::Blank) {
+        items.pop();
+    }
+    for item in &mut items {
+        if let Item::Comment(ref mut comment) = *item {
+            let trimmed = comment.trim_end().len();
+            comment.truncate(trimmed);
+        }
+    }
+
+    Ok(to_source(&items))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{format, parse, to_source, Database, Item, Entry, Operator, Overlay, Strength, StrengthOp};
+    use std::fs::File;
+    use std::io::Read;
+
+    fn read(path: &str) -> String {
+        let mut source = String::new();
+        File::open(path).unwrap().read_to_string(&mut source).unwrap();
+        source
+    }
+
+    fn entries(items: Vec<Item>) -> Vec<Entry> {
+        items.into_iter().filter_map(|item| match item {
+            Item::Entry(entry) => Some(entry),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn parse_images_png() {
+        let items = parse(&read("data/tests/db-images-png")).ok().unwrap();
+        assert_eq!(items[9], Item::Comment(">26 byte 0 deflate/32K,".to_string()));
+
+        let entries = entries(items);
+        assert_eq!(entries.len(), 11);
+        assert_eq!(entries[0], Entry{
+            level: 0,
+            offset: "0".to_string(),
+            kind: "string".to_string(),
+            operator: Operator::Equal,
+            value: "\\x89PNG\\x0d\\x0a\\x1a\\x0a".to_string(),
+            message: "PNG image data".to_string(),
+            mime: Some("image/png".to_string()),
+            strength: None,
+            extensions: None,
+            apple: None,
+        });
+        assert_eq!(entries[1].level, 1);
+        assert_eq!(entries[1].operator, Operator::Any);
+        assert_eq!(entries[1].message, "\\b, %d x");
+    }
+
+    #[test]
+    fn parse_python() {
+        let entries = entries(parse(&read("data/tests/db-python")).ok().unwrap());
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3].kind, "search/1");
+        assert_eq!(entries[3].value, "#!\\ /usr/bin/env\\ python");
+        assert_eq!(entries[3].message, "Python script text executable");
+    }
+
+    #[test]
+    fn parse_operators_and_strength() {
+        let source = ">>&4 lelong&0xff >3 big\n!:strength * 2\n>(4.l) string !foo\n0 name part\n";
+        let entries = entries(parse(source).ok().unwrap());
+
+        assert_eq!(entries[0].level, 2);
+        assert_eq!(entries[0].offset, "&4");
+        assert_eq!(entries[0].kind, "lelong&0xff");
+        assert_eq!(entries[0].operator, Operator::GreaterThan);
+        assert_eq!(entries[0].value, "3");
+        assert_eq!(entries[0].strength, Some(Strength{op: StrengthOp::Multiply, value: 2,}));
+        assert_eq!(entries[1].operator, Operator::NotEqual);
+        assert_eq!(entries[1].message, "");
+        assert_eq!(entries[2].kind, "name");
+        assert_eq!(entries[2].value, "part");
+    }
+
+    #[test]
+    fn format_layout() {
+        let source = "\n\n0 string =RMGC  Rust magic\n!:mime   application/x-rmgc\n\n\n#  comment  \n>4   byte     x \n>5\tstring\t=x\n>6 string \\<\n!:strength + 10\n\n";
+        assert_eq!(format(source).ok().unwrap(), "0\tstring\tRMGC\tRust magic\n!:mime\tapplication/x-rmgc\n\n#  comment\n>4\tbyte\tx\n>5\tstring\t=x\n>6\tstring\t\\<\n!:strength\t+10\n");
+    }
+
+    #[test]
+    fn format_is_stable() {
+        let formatted = format(&read("data/tests/db-images-png")).ok().unwrap();
+        assert_eq!(format(&formatted).ok().unwrap(), formatted);
+        assert_eq!(parse(&formatted).ok().unwrap(), parse(&read("data/tests/db-images-png")).ok().unwrap());
+    }
+
+    #[test]
+    fn edit_and_emit() {
+        use super::super::{Cookie, flags};
+
+        let mut items = parse(&read("data/tests/db-raw-label")).ok().unwrap();
+        if let Item::Entry(ref mut entry) = items[0] {
+            entry.mime = Some("application/x-rmgc".to_string());
+            entry.strength = Some(Strength{op: StrengthOp::Add, value: 20,});
+        }
+        let source = to_source(&items);
+        assert_eq!(parse(&source).ok().unwrap(), items);
+
+        let path = std::env::temp_dir().join(format!("rust-magic-emit-{}", std::process::id()));
+        std::fs::write(&path, &source).unwrap();
+        let cookie = Cookie::open(flags::MIME_TYPE).ok().unwrap();
+        let loaded = cookie.load(&[&path]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(loaded.is_ok());
+        assert_eq!(cookie.buffer(b"RMGCtest").ok().unwrap(), "application/x-rmgc");
+    }
+
+    #[test]
+    fn database_queries() {
+        let db = Database::parse(&read("data/tests/db-python")).ok().unwrap();
+        assert_eq!(db.entries().count(), 4);
+        assert_eq!(db.rules_for_mime("text/x-python").len(), 4);
+        assert!(db.rules_for_mime("image/png").is_empty());
+
+        assert_eq!(db.rules_matching_description(|message| message.starts_with("Python script")).len(), 4);
+        assert!(db.rules_matching_description(|message| message.contains("PNG")).is_empty());
+    }
+
+    #[test]
+    fn database_subset() {
+        let source = "0 name chunk\n>0 byte 1 one\n\n0 string A a\n>1 use chunk\n>>0 byte x\n!:mime application/x-a\n\n# b\n0 string B b\n!:mime application/x-b\n";
+        let db = Database::parse(source).ok().unwrap();
+
+        let subset = db.subset(&["application/x-a"]);
+        assert_eq!(to_source(&subset.items), "0\tname\tchunk\n>0\tbyte\t1\tone\n\n0\tstring\tA\ta\n>1\tuse\tchunk\n>>0\tbyte\tx\n!:mime\tapplication/x-a\n");
+
+        let subset = db.subset(&["application/x-b"]);
+        assert_eq!(to_source(&subset.items), "0\tstring\tB\tb\n!:mime\tapplication/x-b\n");
+
+        assert!(db.subset(&[]).items.is_empty());
+    }
+
+    #[test]
+    fn overlay() {
+        let db = Database::parse("0 name chunk\n>0 byte 1 one\n0 string A a\n>1 string B b\n0 string C c\n").ok().unwrap();
+        let overlay = Overlay::new().disable_rule("a").disable_rule("chunk").disable_file("data/tests/db-python");
+
+        assert_eq!(to_source(&overlay.apply(&db).items), "0\tstring\tC\tc\n");
+        assert!(!overlay.allows_file(std::path::Path::new("data/tests/db-python")));
+        assert!(overlay.allows_file(std::path::Path::new("data/tests/db-images-png")));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse("!:mime text/plain").err().unwrap().line, 1);
+        assert_eq!(parse("0 string foo\n!:bogus x").err().unwrap().line, 2);
+        assert_eq!(parse("\n0 string").err().unwrap().line, 2);
+    }
+}